@@ -0,0 +1,43 @@
+//! A global allocator for the tests and benchmarks that counts allocations
+//! per thread, so they can check how many a code path makes.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+struct CountingAllocator;
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+thread_local!(static ALLOCATIONS: Cell<usize> = Cell::new(0));
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        // Not counted once this thread's locals are gone, e.g. while it exits
+        let _res = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+        return System.alloc(layout);
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+    }
+}
+
+/// Run `func`, returning how many allocations it made on this thread along
+/// with its result.
+pub fn count_allocations<F, R>(func: F) -> (usize, R)
+    where F: FnOnce() -> R
+{
+    let before = ALLOCATIONS.with(|count| count.get());
+    let res = func();
+    return (ALLOCATIONS.with(|count| count.get()) - before, res);
+}
+
+#[test]
+fn test_count_allocations() {
+    let (allocations, boxed) = count_allocations(|| (Box::new(1), Box::new(2)));
+    assert_eq!(allocations, 2);
+    assert_eq!(*boxed.0 + *boxed.1, 3);
+    // Zero sized values don't allocate
+    assert_eq!(count_allocations(|| Box::new(())).0, 0);
+}
@@ -0,0 +1,49 @@
+use std::ops::{Deref, DerefMut};
+
+/// Pads and aligns a `T` to its own cache line, assumed to be 64 bytes, so
+/// that threads hammering neighbouring data don't invalidate each other's
+/// copy of the line (false sharing).
+#[repr(align(64))]
+pub struct CachePadded<T> {
+    value: T,
+}
+
+impl<T> CachePadded<T> {
+    pub const fn new(value: T) -> CachePadded<T> {
+        CachePadded { value: value }
+    }
+}
+
+impl<T> Deref for CachePadded<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+impl<T> DerefMut for CachePadded<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.value
+    }
+}
+
+#[test]
+fn is_cache_line_64_bytes() {
+    use std::mem;
+    assert_eq!(mem::size_of::<CachePadded<u8>>(), 64);
+    assert_eq!(mem::align_of::<CachePadded<u8>>(), 64);
+    // Larger values are padded out to a whole number of lines
+    assert_eq!(mem::size_of::<CachePadded<[u8; 65]>>(), 128);
+}
+
+#[test]
+fn neighbours_on_separate_lines() {
+    let pair = (CachePadded::new(1u8), CachePadded::new(2u8));
+    let first = &*pair.0 as *const u8 as usize;
+    let second = &*pair.1 as *const u8 as usize;
+    assert_eq!(first % 64, 0);
+    assert_eq!(second % 64, 0);
+    assert!(first / 64 != second / 64);
+    assert_eq!(*pair.1, 2);
+}
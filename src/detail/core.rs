@@ -1,102 +1,165 @@
 use std::boxed::{Box, FnBox};
 use std::cell::UnsafeCell;
+use std::fmt::Debug;
 use std::io::{Error, ErrorKind};
+use std::marker::PhantomData;
 use std::mem;
-use std::ptr;
+use std::panic::{self, AssertUnwindSafe};
+use std::u8;
 use std::sync::atomic::{AtomicBool, AtomicIsize, AtomicUsize,Ordering};
 use std::sync::Arc;
 
+use detail::cachepadded::CachePadded;
+use error::FutureError;
 use executor::{Executor};
-use microspinlock::MicroSpinLock;
+use microspinlock::{MicroSpinLock, Sleeper};
+use request::RequestContext;
 use scopeguard::ScopeGuard;
 use try::Try;
 
-/// Assume a cache line is 64 bytes
-#[repr(simd)]
-struct CacheLine(u64, u64, u64, u64, u64, u64, u64, u64);
-/// Helper for aligning a possibly smaller piece of data
-/// to different sizes.
-struct AlignedAs<T, A>(T, [A; 0]);
+/// A state that can be stored in an FSM. States are encoded to a u8 so
+/// they can live in an atomic, and decoding checks the value is a valid
+/// state rather than transmuting it.
+pub trait FSMState: Copy + PartialEq + Debug {
+    fn to_u8(self) -> u8;
+    fn from_u8(val: u8) -> Option<Self>;
 
-impl<T, A> AlignedAs<T, A> {
-    pub fn new(item: T) -> AlignedAs<T, A> {
-        return AlignedAs(item, []);
-    }
-
-    pub fn get(self) -> T {
-        return self.0;
+    /// Whether the state machine has an edge from `from` to `to`, checked
+    /// on every transition when the core-assertions feature is on.
+    fn is_legal_transition(_from: Self, _to: Self) -> bool {
+        return true;
     }
 }
 
-#[test]
-fn is_cache_line_64_bytes() {
-    use std::mem;
-    assert_eq!(mem::size_of::<CacheLine>(), 64);
-}
-
 /// A helper struct for writing Finite State Machines
-/// TODO(ptc) Make state an enum type param if we can
-/// find a way to encode Enum's to usize and create a
-/// trait bound for T s.t. that ensures correctness
 /// TODO(ptc) would be nice to have AtomicU8 as well
 /// so that we don't have to do `as usize` everywhere
 /// which is probably having to zero extend State everywhere
-pub struct FSM {
-    lock: MicroSpinLock,
+pub struct FSM<S>
+    where S: FSMState
+{
     state: AtomicUsize,
+    phantom: PhantomData<S>,
 }
 
-impl FSM {
-    pub fn new(start: State) -> FSM {
+/// Set in the state word while a transition's action runs, in place of a
+/// separate lock. The top bit, so it can't be mistaken for a state.
+const TRANSITIONING: usize = !(!0 >> 1);
+
+impl<S> FSM<S>
+    where S: FSMState
+{
+    pub fn new(start: S) -> FSM<S> {
         FSM {
-            lock: MicroSpinLock::new(),
-            state: AtomicUsize::new(start as usize),
+            state: AtomicUsize::new(start.to_u8() as usize),
+            phantom: PhantomData,
         }
     }
 
     /// Atomically do a state transition with accompanying action.
     /// The action will see the old state.
     /// returns true on success, false and action unexecuted otherwise
-    pub fn update_state<F>(&self, old_state: State, new_state: State, action: F) -> bool
+    pub fn update_state<F>(&self, old_state: S, new_state: S, action: F) -> bool
         where F: FnOnce()
     {
-        if !self.lock.try_lock() {
-            self.lock.lock();
-        }
-        if self.state.load(Ordering::Acquire) != (old_state as usize) {
-            self.lock.unlock();
-            return false;
+        FSM::check_transition(old_state, new_state);
+        let old = old_state.to_u8() as usize;
+        // Uncontended this is a single CAS, claiming the transition by
+        // setting TRANSITIONING. Only if another transition is running do
+        // we back off and retry.
+        let mut sleeper = Sleeper::new();
+        loop {
+            match self.state
+                .compare_exchange(old, old | TRANSITIONING, Ordering::Acquire, Ordering::Relaxed) {
+                Ok(_) => break,
+                Err(current) if current & TRANSITIONING != 0 => sleeper.wait(),
+                Err(_) => return false,
+            }
         }
         action();
-        self.state.store(new_state as usize, Ordering::Release);
-        self.lock.unlock();
+        self.state.store(new_state.to_u8() as usize, Ordering::Release);
         return true;
     }
 
-    pub fn update_state2<F1, F2>(&self,
-                                 old_state: State,
-                                 new_state: State,
-                                 protected_action: F1,
-                                 unprotected_action: F2)
-                                 -> bool
-        where F1: FnOnce(),
-              F2: FnOnce()
+    /// Move from `old_state` to `new_state` with a single CAS and no
+    /// action. For a caller that has already written whatever the new state
+    /// guards to memory only it writes: the CAS releases those writes to
+    /// whoever next sees the new state, and acquires the other side's.
+    /// Waits out a with_state or update_state in progress. Returns false if
+    /// the state wasn't `old_state`.
+    pub fn transition(&self, old_state: S, new_state: S) -> bool {
+        FSM::check_transition(old_state, new_state);
+        let old = old_state.to_u8() as usize;
+        let new = new_state.to_u8() as usize;
+        let mut sleeper = Sleeper::new();
+        loop {
+            match self.state.compare_exchange(old, new, Ordering::AcqRel, Ordering::Relaxed) {
+                Ok(_) => return true,
+                Err(current) if current & TRANSITIONING != 0 => sleeper.wait(),
+                Err(_) => return false,
+            }
+        }
+    }
+
+    /// Staying in the same state, e.g. to hold it while reading, doesn't
+    /// count as a transition.
+    #[cfg(feature = "core-assertions")]
+    #[inline(always)]
+    fn check_transition(old_state: S, new_state: S) {
+        if old_state != new_state && !S::is_legal_transition(old_state, new_state) {
+            panic!("illegal FSM transition from {:?} to {:?}", old_state, new_state);
+        }
+    }
+
+    #[cfg(not(feature = "core-assertions"))]
+    #[inline(always)]
+    fn check_transition(_old_state: S, _new_state: S) {}
+
+    /// Run `action` on the current state while no transition can happen
+    /// until it returns.
+    pub fn with_state<F, R>(&self, action: F) -> R
+        where F: FnOnce(S) -> R
     {
-        let result = self.update_state(old_state, new_state, protected_action);
-        if result {
-            unprotected_action();
+        let mut sleeper = Sleeper::new();
+        loop {
+            let current = self.state.load(Ordering::Relaxed);
+            if current & TRANSITIONING != 0 {
+                sleeper.wait();
+                continue;
+            }
+            if self.state
+                .compare_exchange(current,
+                                  current | TRANSITIONING,
+                                  Ordering::Acquire,
+                                  Ordering::Relaxed)
+                .is_ok() {
+                let result = action(FSM::<S>::decode(current));
+                self.state.store(current, Ordering::Release);
+                return result;
+            }
         }
-        result
     }
 
-    pub fn get_state(&self) -> State {
-        unsafe {
-            return mem::transmute(self.state.load(Ordering::Acquire) as u8);
+    /// Panics if the stored value isn't a valid state, which can only
+    /// happen if the FSM has been corrupted.
+    pub fn get_state(&self) -> S {
+        // Mid transition this is still the old state
+        return FSM::<S>::decode(self.state.load(Ordering::Acquire) & !TRANSITIONING);
+    }
+
+    fn decode(val: usize) -> S {
+        // Check the range first so a corrupt value can't truncate into a
+        // valid one
+        if val <= u8::MAX as usize {
+            if let Some(state) = S::from_u8(val as u8) {
+                return state;
+            }
         }
+        panic!("FSM holds an invalid state discriminant: {}", val);
     }
 }
 
-#[derive(PartialEq, Eq, Debug)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum State {
     Start,
     OnlyResult,
@@ -105,6 +168,40 @@ pub enum State {
     Done,
 }
 
+impl FSMState for State {
+    fn to_u8(self) -> u8 {
+        match self {
+            State::Start => 0,
+            State::OnlyResult => 1,
+            State::OnlyCallback => 2,
+            State::Armed => 3,
+            State::Done => 4,
+        }
+    }
+
+    fn from_u8(val: u8) -> Option<State> {
+        match val {
+            0 => Some(State::Start),
+            1 => Some(State::OnlyResult),
+            2 => Some(State::OnlyCallback),
+            3 => Some(State::Armed),
+            4 => Some(State::Done),
+            _ => None,
+        }
+    }
+
+    fn is_legal_transition(from: State, to: State) -> bool {
+        match (from, to) {
+            (State::Start, State::OnlyResult) |
+            (State::Start, State::OnlyCallback) |
+            (State::OnlyResult, State::Armed) |
+            (State::OnlyCallback, State::Armed) |
+            (State::Armed, State::Done) => true,
+            _ => false,
+        }
+    }
+}
+
 #[test]
 fn back_and_forth_state() {
     assert_eq!(FSM::new(State::Start).get_state(), State::Start);
@@ -115,80 +212,255 @@ fn back_and_forth_state() {
     assert_eq!(FSM::new(State::Done).get_state(), State::Done);
 }
 
+// Walks edges Core never takes, which core-assertions forbids
+#[cfg(not(feature = "core-assertions"))]
+#[test]
+fn update_through_all_states() {
+    let states = [State::Start,
+                  State::OnlyResult,
+                  State::OnlyCallback,
+                  State::Armed,
+                  State::Done];
+    let fsm = FSM::new(State::Start);
+    for pair in states.windows(2) {
+        assert_eq!(fsm.get_state(), pair[0]);
+        // Transitions from the wrong state are refused
+        assert!(!fsm.update_state(pair[1], pair[0], || {}));
+        assert!(fsm.update_state(pair[0], pair[1], || {}));
+    }
+    assert_eq!(fsm.get_state(), State::Done);
+    for state in states.iter() {
+        assert_eq!(State::from_u8(state.to_u8()), Some(*state));
+    }
+    assert_eq!(State::from_u8(5), None);
+}
+
+#[cfg(not(feature = "core-assertions"))]
+#[test]
+fn transition_through_all_states() {
+    let states = [State::Start,
+                  State::OnlyResult,
+                  State::OnlyCallback,
+                  State::Armed,
+                  State::Done];
+    let fsm = FSM::new(State::Start);
+    for pair in states.windows(2) {
+        assert!(!fsm.transition(pair[1], pair[0]));
+        assert!(fsm.transition(pair[0], pair[1]));
+        assert_eq!(fsm.get_state(), pair[1]);
+    }
+}
+
+#[cfg(not(feature = "core-assertions"))]
+#[test]
+fn update_state_is_exclusive() {
+    use microspinlock::spawn_unsafe;
+
+    // Bumped by transition actions without synchronization of its own
+    struct Unsynced(UnsafeCell<u64>);
+    unsafe impl Sync for Unsynced {}
+
+    let fsm = FSM::new(State::Start);
+    let actions = Unsynced(UnsafeCell::new(0));
+    let transitions = AtomicUsize::new(0);
+    let mut children = Vec::new();
+    for _ in 0..8 {
+        children.push(unsafe {
+            spawn_unsafe(|| {
+                for _ in 0..10000 {
+                    for &(from, to) in [(State::Start, State::OnlyResult),
+                                        (State::OnlyResult, State::Start)]
+                        .iter() {
+                        let bump = || unsafe { *actions.0.get() += 1 };
+                        if fsm.update_state(from, to, bump) {
+                            transitions.fetch_add(1, Ordering::Relaxed);
+                        }
+                    }
+                }
+            })
+        });
+    }
+    for child in children {
+        child.join().unwrap();
+    }
+    assert_eq!(unsafe { *actions.0.get() }, transitions.load(Ordering::Relaxed) as u64);
+}
+
+#[test]
+#[should_panic(expected = "FSM holds an invalid state discriminant: 42")]
+fn invalid_state_panics() {
+    let fsm = FSM::new(State::Start);
+    fsm.state.store(42, Ordering::Release);
+    fsm.get_state();
+}
+
+#[test]
+#[should_panic(expected = "FSM holds an invalid state discriminant: 256")]
+fn invalid_state_doesnt_truncate() {
+    let fsm = FSM::new(State::Start);
+    fsm.state.store(256, Ordering::Release);
+    fsm.get_state();
+}
+
+#[test]
+fn legal_transitions() {
+    let legal = [(State::Start, State::OnlyResult),
+                 (State::Start, State::OnlyCallback),
+                 (State::OnlyResult, State::Armed),
+                 (State::OnlyCallback, State::Armed),
+                 (State::Armed, State::Done)];
+    let states = [State::Start,
+                  State::OnlyResult,
+                  State::OnlyCallback,
+                  State::Armed,
+                  State::Done];
+    for &from in states.iter() {
+        for &to in states.iter() {
+            assert_eq!(State::is_legal_transition(from, to),
+                       legal.contains(&(from, to)));
+        }
+    }
+    // Each edge is taken without complaint, with or without core-assertions
+    for &(from, to) in legal.iter() {
+        assert!(FSM::new(from).transition(from, to));
+        assert!(FSM::new(from).update_state(from, to, || {}));
+    }
+}
+
+#[cfg(feature = "core-assertions")]
+#[test]
+#[should_panic(expected = "illegal FSM transition from Done to Start")]
+fn illegal_transition_panics() {
+    let fsm = FSM::new(State::Done);
+    fsm.transition(State::Done, State::Start);
+}
+
 /// Core is the shared struct between Future and Promise that
 /// implements the core functionality
-pub struct Core<T> {
+///
+/// The result's error type `E` defaults to io::Error. Errors about the Core
+/// itself, such as calling set_result twice, are still io::Errors.
+pub struct Core<T, E = Error> {
     /// TODO(ptc) See if we can do the actual trick of C++ style placement
     /// new of the Box<FnBox()> into callback or if that's just faulty
     /// translation/thinking
-    callback: UnsafeCell<Box<FnBox(Try<T>) + 'static>>,
-    result: UnsafeCell<Option<Try<T>>>,
-    state: FSM,
+    callback: UnsafeCell<Box<FnBox(Try<T, E>) + Send + 'static>>,
+    result: UnsafeCell<Option<Try<T, E>>>,
+    // The state and counters are touched by both the Promise and the Future
+    // thread, so each gets its own cache line.
+    state: CachePadded<FSM<State>>,
     /// TODO(ptc) Shouldn't need an entire u64 to store the number of attached
-    attached: AtomicIsize,
-    active: AtomicBool,
+    attached: CachePadded<AtomicIsize>,
+    active: CachePadded<AtomicBool>,
     interrupt_handler_set: AtomicBool,
     interrupt_lock: MicroSpinLock,
     executor_lock: MicroSpinLock,
     priority: i8,
-    executor: *const Executor,
-    context: Arc<RequestContext>,
+    executor: Option<Arc<Executor + Send + Sync>>,
+    /// How do_callback hands the callback to `executor`, see
+    /// dispatch_callback. Set along with it.
+    dispatch: Option<fn(&Core<T, E>, &Executor)>,
+    /// The error detach_promise fails an unfulfilled Core with. Set by new(),
+    /// where E is known to convert from an io::Error.
+    broken_promise: Option<fn() -> E>,
+    context: UnsafeCell<Arc<RequestContext>>,
     interrupt: UnsafeCell<Option<Error>>,
-    interrupt_handler: UnsafeCell<Option<Arc<Fn(&Error)>>>,
+    interrupt_handler: UnsafeCell<Option<Arc<Fn(&Error) + Send + Sync>>>,
+    /// Told when the result is set, without being handed it
+    ready_callback: UnsafeCell<Option<Box<FnBox() + Send>>>,
 }
 
-struct NullExecutor(usize, usize);
+/// Lets do_callback hand the Core to its executor. The Core stays alive
+/// until the work runs, since do_callback counts it as attached.
+struct CorePtr<T, E>(*const Core<T, E>);
+
+/// The callback is Send, so the work only needs T and E to be for it to run
+/// on the executor's thread, where it takes the result.
+unsafe impl<T: Send, E: Send> Send for CorePtr<T, E> {}
 
-unsafe fn null_executor() -> *const Executor {
-    return mem::transmute([0 as usize; 2]);
+/// Hand `core`'s callback to `executor`, to run wherever the executor runs
+/// it. Only set_executor, where T and E are known to be Send, can pick this
+/// for a Core; a Core with no executor runs its callback inline.
+fn dispatch_callback<T, E>(core: &Core<T, E>, executor: &Executor)
+    where T: Send + 'static,
+          E: Send + 'static
+{
+    let core = CorePtr(core as *const Core<T, E>);
+    executor.add(Box::new(move || {
+        unsafe {
+            (*core.0).run_callback();
+        }
+    }));
 }
 
-impl<T> Core<T> {
-    pub fn new() -> Core<T> {
+/// What a Promise dropped before fulfilling its Core leaves in it.
+fn broken_promise<E>() -> E
+    where E: From<Error>
+{
+    return E::from(FutureError::BrokenPromise.into());
+}
+
+impl<T, E> Core<T, E> {
+    pub fn new() -> Core<T, E>
+        where E: From<Error>
+    {
         Core {
             callback: UnsafeCell::new(Box::new(|_| {})),
             result: UnsafeCell::new(None),
-            state: FSM::new(State::Start),
-            attached: AtomicIsize::new(2),
-            active: AtomicBool::new(true),
+            state: CachePadded::new(FSM::new(State::Start)),
+            attached: CachePadded::new(AtomicIsize::new(2)),
+            active: CachePadded::new(AtomicBool::new(true)),
             interrupt_handler_set: AtomicBool::new(false),
             interrupt_lock: MicroSpinLock::new(),
             executor_lock: MicroSpinLock::new(),
             priority: -1,
-            // TODO(ptc) fix this when ptr::null doesn't need to be sized
-            executor: unsafe { null_executor() },
-            context: Arc::new(RequestContext::new()),
+            executor: None,
+            dispatch: None,
+            broken_promise: Some(broken_promise::<E>),
+            context: UnsafeCell::new(Arc::new(RequestContext::new())),
             interrupt: UnsafeCell::new(None),
             interrupt_handler: UnsafeCell::new(None),
+            ready_callback: UnsafeCell::new(None),
         }
     }
 
-    pub fn new_try(try: Try<T>) -> Core<T> {
+    pub fn new_try(try: Try<T, E>) -> Core<T, E> {
         Core {
             callback: UnsafeCell::new(Box::new(|_| {})),
             result: UnsafeCell::new(Some(try)),
-            state: FSM::new(State::OnlyResult),
-            attached: AtomicIsize::new(1),
-            active: AtomicBool::new(true),
+            state: CachePadded::new(FSM::new(State::OnlyResult)),
+            attached: CachePadded::new(AtomicIsize::new(1)),
+            active: CachePadded::new(AtomicBool::new(true)),
             interrupt_handler_set: AtomicBool::new(false),
             interrupt_lock: MicroSpinLock::new(),
             executor_lock: MicroSpinLock::new(),
             priority: -1,
-            // TODO(ptc) fix this when ptr::null doesn't need to be sized
-            executor: unsafe { null_executor() },
-            context: Arc::new(RequestContext::new()),
+            executor: None,
+            dispatch: None,
+            broken_promise: None,
+            context: UnsafeCell::new(Arc::new(RequestContext::new())),
             interrupt: UnsafeCell::new(None),
             interrupt_handler: UnsafeCell::new(None),
+            ready_callback: UnsafeCell::new(None),
         }
     }
 
+    /// Drop one reference to the Core, freeing it when that was the last.
+    ///
+    /// `attached` counts the Future side, the Promise side and any callback
+    /// queued by do_callback. A Core from new() starts at 2, one for each
+    /// side, whether or not the Future has been handed out yet; a Promise
+    /// that never handed out its Future detaches both sides itself. A Core
+    /// from new_try() has no Promise side and starts at 1. Every Core that
+    /// can reach zero was allocated with Box::into_raw.
     fn detach_one(&self) -> () {
         let attached = self.attached.fetch_sub(1, Ordering::SeqCst) - 1;
         assert!(attached >= 0);
         assert!(attached <= 2);
         if attached == 0 {
-            // TODO(ptc) make sure this actually runs the destructor
-            mem::drop(self)
+            unsafe {
+                mem::drop(Box::from_raw(self as *const Core<T, E> as *mut Core<T, E>));
+            }
         }
     }
 
@@ -198,6 +470,34 @@ impl<T> Core<T> {
         self.detach_one();
     }
 
+    /// Count a Future side again after the first Future was dropped before
+    /// the result was set and without setting a callback. The executor and
+    /// ready callback the old Future set are cleared, so the new Future
+    /// starts out as the first one did; the Promise's interrupt handler, and
+    /// any interrupt the old Future already raised on it, are kept. Returns
+    /// false, changing nothing, if the old Future is still attached or had
+    /// set a callback. Call only from the Promise thread.
+    pub fn reattach_future(&mut self) -> bool {
+        // With the Promise attached the count can't drop to 0 under us, and
+        // only a Future could raise it
+        if self.attached.load(Ordering::SeqCst) != 1 || self.state.get_state() != State::Start {
+            return false;
+        }
+        // No Future is left to touch its half of the Core
+        if !self.executor_lock.try_lock() {
+            self.executor_lock.lock();
+        }
+        self.executor = None;
+        self.dispatch = None;
+        self.priority = -1;
+        self.executor_lock.unlock();
+        unsafe {
+            *self.ready_callback.get() = None;
+        }
+        self.attached.fetch_add(1, Ordering::SeqCst);
+        return true;
+    }
+
     /// Called by a destructing Promise from the Promise thread
     pub fn detach_promise(&self) {
         // detach_promise() and set_result() should never be called in parallel
@@ -205,92 +505,110 @@ impl<T> Core<T> {
         unsafe {
             // TODO(ptc) use UNLIKELY here
             if (*self.result.get()).is_none() {
-                self.set_result(Try::new_error(Error::new(ErrorKind::Other, "Broken Promise")));
+                if let Some(broken_promise) = self.broken_promise {
+                    let _res = self.set_result(Try::new_error(broken_promise()));
+                }
             }
         }
         self.detach_one();
     }
 
     /// Call only from Future thread
+    ///
+    /// With one Future side and one Promise side, set_callback and
+    /// set_result need no lock: each writes only its own half of the Core,
+    /// the callback or the result, then publishes it with one CAS on the
+    /// state. The other side only reads that half once the state says it is
+    /// there, and whichever side arms the Core runs the callback.
     pub fn set_callback<F>(&self, func: F) -> Result<(), Error>
-        where F: FnOnce(Try<T>) + 'static
+        where F: FnOnce(Try<T, E>) + Send + 'static
     {
-        let mut transition_to_armed = false;
-        let callback: UnsafeCell<Box<FnBox(Try<T>) + 'static>> = UnsafeCell::new(Box::new(func));
-        let mut set_callback_ = || unsafe {
-            let context = RequestContext::save_context();
+        let mut state = self.state.get_state();
+        if state != State::Start && state != State::OnlyResult {
+            return Err(Error::new(ErrorKind::Other, "logic error: set_callback called twice"));
+        }
+        unsafe {
+            *self.context.get() = RequestContext::save_context();
 
             // TODO(ptc) if we do change to having a space to put the lambda
             // inline with the Core object, here is where we would check the
             // size of the callback and place there if it fits
 
-            ptr::swap(self.callback.get(), callback.get());
-        };
-        let mut done = false;
-        while !done {
-            let state = self.state.get_state();
+            *self.callback.get() = Box::new(func);
+        }
+        loop {
             match state {
                 State::Start => {
-                    done = self.state.update_state(state, State::OnlyCallback, &mut set_callback_);
+                    if self.state.transition(state, State::OnlyCallback) {
+                        return Ok(());
+                    }
                 }
                 State::OnlyResult => {
-                    done = self.state.update_state(state, State::Armed, &mut set_callback_);
-                    transition_to_armed = true;
-                }
-                State::OnlyCallback => {
-                    return Err(Error::new(ErrorKind::Other,
-                                          "logic error: set_callback called twice"));
-                }
-                State::Armed => {
-                    return Err(Error::new(ErrorKind::Other,
-                                          "logic error: set_callback called twice"));
-                }
-                State::Done => {
-                    return Err(Error::new(ErrorKind::Other,
-                                          "logic error: set_callback called twice"));
+                    if self.state.transition(state, State::Armed) {
+                        self.maybe_callback();
+                        return Ok(());
+                    }
                 }
+                // Only the Promise side moved it, and only from Start
+                _ => unreachable!(),
             }
+            state = self.state.get_state();
         }
+    }
 
-        if transition_to_armed {
-            self.maybe_callback();
+    /// Call `func` once the result is set, on whichever thread sets it, or
+    /// right away if it already is. Unlike set_callback this doesn't take
+    /// the result. Replaces any earlier ready callback not yet called.
+    pub fn set_ready_callback(&self, func: Box<FnBox() + Send>) {
+        let ready = self.state.with_state(|state| {
+            match state {
+                State::Start | State::OnlyCallback => {
+                    unsafe {
+                        *self.ready_callback.get() = Some(func);
+                    }
+                    None
+                }
+                _ => Some(func),
+            }
+        });
+        if let Some(func) = ready {
+            func();
         }
-        return Ok(());
     }
 
-    /// Call only from Promise thread
-    pub fn set_result(&self, res: Try<T>) -> Result<(), Error> {
+    /// Call only from Promise thread, see set_callback
+    pub fn set_result(&self, res: Try<T, E>) -> Result<(), Error> {
+        let mut state = self.state.get_state();
+        if state != State::Start && state != State::OnlyCallback {
+            return Err(Error::new(ErrorKind::Other, "logic error: set_result called twice"));
+        }
+        unsafe {
+            *self.result.get() = Some(res);
+        }
         let mut transition_to_armed = false;
-        let res = UnsafeCell::new(Some(res));
-        let mut set_result_ = || unsafe {
-            ptr::swap(self.result.get(), res.get());
-        };
-        // TODO(ptc) investigate porting over the FSM_START/FSM_UPDATE/FSM_CASE
-        // macros
-        let mut done = false;
-        while !done {
-            let state = self.state.get_state();
+        loop {
             match state {
                 State::Start => {
-                    done = self.state.update_state(state, State::OnlyResult, &mut set_result_);
+                    if self.state.transition(state, State::OnlyResult) {
+                        break;
+                    }
                 }
                 State::OnlyCallback => {
-                    done = self.state.update_state(state, State::Armed, &mut set_result_);
-                    transition_to_armed = true;
-                }
-                State::OnlyResult => {
-                    return Err(Error::new(ErrorKind::Other,
-                                          "logic error: set_result called twice"));
-                }
-                State::Armed => {
-                    return Err(Error::new(ErrorKind::Other,
-                                          "logic error: set_result called twice"));
-                }
-                State::Done => {
-                    return Err(Error::new(ErrorKind::Other,
-                                          "logic error: set_result called twice"));
+                    if self.state.transition(state, State::Armed) {
+                        transition_to_armed = true;
+                        break;
+                    }
                 }
+                // Only the Future side moved it, and only from Start
+                _ => unreachable!(),
             }
+            state = self.state.get_state();
+        }
+        // set_ready_callback only stores a ready callback under with_state
+        // while there is no result, so now there is this can't race it
+        let ready_callback = unsafe { (*self.ready_callback.get()).take() };
+        if let Some(ready_callback) = ready_callback {
+            ready_callback();
         }
         if transition_to_armed {
             self.maybe_callback();
@@ -298,22 +616,35 @@ impl<T> Core<T> {
         return Ok(());
     }
 
-    pub fn set_executor(&mut self, exec: *const Executor, priority: i8) {
+    /// Run the callback on `exec` rather than inline. That may well be on
+    /// another thread, so only for a Send T and E.
+    pub fn set_executor(&mut self, exec: Arc<Executor + Send + Sync>, priority: i8)
+        where T: Send + 'static,
+              E: Send + 'static
+    {
         if !self.executor_lock.try_lock() {
             self.executor_lock.lock();
         }
-        self.executor = exec;
-        self.priority = priority;
+        self.set_executor_nolock(exec, priority);
         self.executor_lock.unlock();
     }
 
-    fn set_executor_nolock(&mut self, exec: *const Executor, priority: i8) {
-        self.executor = exec;
+    fn set_executor_nolock(&mut self, exec: Arc<Executor + Send + Sync>, priority: i8)
+        where T: Send + 'static,
+              E: Send + 'static
+    {
+        self.executor = Some(exec);
+        self.dispatch = Some(dispatch_callback::<T, E>);
         self.priority = priority;
     }
 
-    pub fn get_executor(&self) -> *const Executor {
-        return self.executor;
+    pub fn get_executor(&self) -> Option<Arc<Executor + Send + Sync>> {
+        if !self.executor_lock.try_lock() {
+            self.executor_lock.lock();
+        }
+        let executor = self.executor.clone();
+        self.executor_lock.unlock();
+        return executor;
     }
 
     /// May call from any thread
@@ -361,7 +692,7 @@ impl<T> Core<T> {
         }
     }
 
-    fn raise(&self, err: Error) {
+    pub fn raise(&self, err: Error) {
         if !self.interrupt_lock.try_lock() {
             self.interrupt_lock.lock();
         }
@@ -381,7 +712,7 @@ impl<T> Core<T> {
     /// Should only be called from Promise thread
     /// Sets the interrupt handler on the Core object, if it already has
     /// an exception/interrupt than just cann the handler on the interrupt
-    fn set_interrupt_handler(&self, handler: Arc<Fn(&Error)>) {
+    pub fn set_interrupt_handler(&self, handler: Arc<Fn(&Error) + Send + Sync>) {
         if !self.interrupt_lock.try_lock() {
             self.interrupt_lock.lock();
         }
@@ -398,14 +729,14 @@ impl<T> Core<T> {
         self.interrupt_lock.unlock();
     }
 
-    pub fn set_interrupt_handler_nolock(&self, handler: Arc<Fn(&Error)>) {
+    pub fn set_interrupt_handler_nolock(&self, handler: Arc<Fn(&Error) + Send + Sync>) {
         self.interrupt_handler_set.store(true, Ordering::Relaxed);
         unsafe {
             *self.interrupt_handler.get() = Some(handler);
         }
     }
 
-    pub fn get_interrupt_handler(&self) -> Option<Arc<Fn(&Error)>> {
+    pub fn get_interrupt_handler(&self) -> Option<Arc<Fn(&Error) + Send + Sync>> {
         if !self.interrupt_handler_set.load(Ordering::Acquire) {
             return None;
         }
@@ -424,33 +755,44 @@ impl<T> Core<T> {
         return self.has_result();
     }
 
-    pub fn get_try(&self) -> Result<Try<T>, Error> {
+    pub fn get_try(&self) -> Result<Try<T, E>, Error> {
         if self.ready() {
             unsafe {
                 return Ok((*self.result.get()).take().unwrap());
             }
         } else {
-            return Err(Error::new(ErrorKind::Other, "Future not ready"));
+            return Err(FutureError::NotReady.into());
         }
     }
 
+    pub fn get_state(&self) -> State {
+        return self.state.get_state();
+    }
+
+    /// The current state and whether a result is stored, read together
+    /// under the FSM lock. For Debug output, the answer may be stale as
+    /// soon as it's returned.
+    pub fn debug_state(&self) -> (State, bool) {
+        return self.state.with_state(|state| {
+            (state, unsafe { (*self.result.get()).is_some() })
+        });
+    }
+
+    /// Borrow the result without taking it. Only possible once the result
+    /// is set and before a callback is, so there is no set_result or
+    /// callback to race with. The borrow must not be held across get_try.
+    pub fn peek_try(&self) -> Option<&Try<T, E>> {
+        let mut result = None;
+        self.state.update_state(State::OnlyResult, State::OnlyResult, || {
+            result = unsafe { (*self.result.get()).as_ref() };
+        });
+        return result;
+    }
+
     fn maybe_callback(&self) {
-        let mut done = false;
-        while !done {
-            let state = self.state.get_state();
-            match state {
-                State::Armed => {
-                    if self.active.load(Ordering::Acquire) {
-                        self.state.update_state2(state, State::Done, || {}, || {
-                            self.do_callback();
-                        });
-                    }
-                    done = true;
-                }
-                _ => {
-                    done = true;
-                }
-            };
+        // Both sides may get here, only the one that moves to Done runs it
+        if self.active.load(Ordering::Acquire) && self.state.transition(State::Armed, State::Done) {
+            self.do_callback();
         }
     }
 
@@ -459,7 +801,8 @@ impl<T> Core<T> {
         if !self.executor_lock.try_lock() {
             self.executor_lock.lock();
         }
-        let executor = self.executor;
+        let executor = self.executor.clone();
+        let dispatch = self.dispatch;
         let _priority = self.priority;
         self.executor_lock.unlock();
 
@@ -467,53 +810,39 @@ impl<T> Core<T> {
         self.attached.fetch_add(1, Ordering::SeqCst);
 
         // See if rust has llvm.expect intrinsic exposed
-        if unsafe { executor != null_executor() } {
-            if unsafe { (*executor).get_num_priorities() == 1 } {
-                scope_exit!(self.detach_one());
-                RequestContext::set_context(self.context.clone());
-                unsafe {
-                    let result = self.result.get();
-                    let callback = mem::replace(&mut (*self.callback.get()), Box::new(|_try| {}));
-                    if let Some(try) = (*result).take() {
-                        callback(try);
-                    }
-                }
-            } else {
+        match (executor, dispatch) {
+            (Some(executor), Some(dispatch)) => {
                 // TODO(ptc) implement add_with_priority to executors
+                dispatch(self, &*executor);
             }
-        } else {
-            scope_exit!(self.detach_one());
-            RequestContext::set_context(self.context.clone());
-            unsafe {
-                let result = self.result.get();
-                let callback = mem::replace(&mut (*self.callback.get()), Box::new(|_try| {}));
-                if let Some(try) = (*result).take() {
-                    callback(try);
-                }
+            _ => {
+                // Like Folly::Future, with no executor the callback runs
+                // inline
+                self.run_callback();
             }
         }
-        // NOTE(ptc) Folly::Future allows executor to be null and then calls
-        // the callback inline. Currently we do not allow that, but maybe
-        // there is a good reason to do so, although unsure why this just
-        // couldn't be done with InlineExecutor.
-    }
-}
-
-/// TODO(ptc) implement RequestContext
-pub struct RequestContext;
-
-impl RequestContext {
-    pub fn new() -> RequestContext {
-        RequestContext
-    }
-
-    pub fn set_context(_ctxt: Arc<RequestContext>) {
-        // TODO(ptc) implement
     }
 
-    pub fn save_context() -> Arc<RequestContext> {
-        // TODO(ptc) implement
-        return Arc::new(RequestContext::new());
+    /// Run the callback on the result, with the RequestContext saved when
+    /// the callback was set, then drop do_callback's reference to the Core.
+    /// A panic in the callback stops here rather than unwinding into
+    /// whoever set the result or into the executor. Whatever the callback
+    /// owned is dropped, so a downstream Promise it held is broken.
+    fn run_callback(&self) {
+        scope_exit!(self.detach_one());
+        let prev_context = RequestContext::set_context(unsafe {
+            (*self.context.get()).clone()
+        });
+        scope_exit!({
+            RequestContext::set_context(prev_context);
+        });
+        unsafe {
+            let result = self.result.get();
+            let callback = mem::replace(&mut (*self.callback.get()), Box::new(|_try| {}));
+            if let Some(try) = (*result).take() {
+                let _res = panic::catch_unwind(AssertUnwindSafe(move || callback(try)));
+            }
+        }
     }
 }
 
@@ -524,10 +853,13 @@ mod tests {
     use std::io::{Error, ErrorKind};
     use std::sync::atomic::{AtomicUsize, Ordering};
     use std::sync::Arc;
+    use std::thread;
     use test::Bencher;
 
     use executor::InlineExecutor;
-    use super::Core;
+    use microspinlock::{MicroSpinLock, spawn_unsafe};
+    use request::RequestContext;
+    use super::{Core, FSM, FSMState, State};
     use try::Try;
 
     #[test]
@@ -582,6 +914,119 @@ mod tests {
         assert!(core.set_result(try).is_err());
     }
 
+    /// Hands one heap allocated Core to both threads of a stress test, the
+    /// way a Promise and its Future share one.
+    #[derive(Clone, Copy)]
+    struct SharedCore<T>(*mut Core<T>);
+
+    unsafe impl<T> Send for SharedCore<T> {}
+
+    impl<T> SharedCore<T> {
+        fn new() -> SharedCore<T> {
+            SharedCore(Box::into_raw(Box::new(Core::new())))
+        }
+
+        fn get(&self) -> &Core<T> {
+            unsafe { &*self.0 }
+        }
+    }
+
+    /// A tiny xorshift generator, so each run races differently without
+    /// pulling in a crate for it.
+    fn next_random(seed: &mut u32) -> u32 {
+        *seed ^= *seed << 13;
+        *seed ^= *seed >> 17;
+        *seed ^= *seed << 5;
+        return *seed;
+    }
+
+    /// Busy wait or yield for a small random while, to shift where in the
+    /// other thread's transition this thread lands.
+    fn random_delay(seed: &mut u32) {
+        let n = next_random(seed) % 256;
+        if n % 16 == 0 {
+            thread::yield_now();
+        } else {
+            let spins = AtomicUsize::new(0);
+            while spins.fetch_add(1, Ordering::Relaxed) < n as usize {}
+        }
+    }
+
+    #[test]
+    fn set_result_set_callback_stress() {
+        for i in 0..2000 {
+            let core: SharedCore<usize> = SharedCore::new();
+            let ran = Arc::new(AtomicUsize::new(0));
+            let callback_ran = ran.clone();
+            let producer = thread::spawn(move || {
+                let mut seed = 2 * i as u32 + 1;
+                random_delay(&mut seed);
+                core.get().set_result(Try::new_value(i)).unwrap();
+            });
+            let consumer = thread::spawn(move || {
+                let mut seed = 2 * i as u32 + 2;
+                random_delay(&mut seed);
+                core.get()
+                    .set_callback(move |try| {
+                        assert_eq!(try.value().unwrap(), i);
+                        callback_ran.fetch_add(1, Ordering::SeqCst);
+                    })
+                    .unwrap();
+            });
+            producer.join().unwrap();
+            consumer.join().unwrap();
+            assert_eq!(ran.load(Ordering::SeqCst), 1);
+            assert_eq!(core.get().get_state(), State::Done);
+            // Both sides let go, freeing the Core
+            core.get().detach_future();
+            core.get().detach_promise();
+        }
+    }
+
+    /// Like set_result_set_callback_stress, but without delays and over a
+    /// batch of Cores at a time, half of them with an executor, and with a
+    /// ready callback racing set_result too.
+    #[test]
+    fn set_result_set_callback_batch_stress() {
+        let ready = Arc::new(AtomicUsize::new(0));
+        for batch in 0..50 {
+            let mut cores: Vec<Core<usize>> = (0..1000).map(|_| Core::new()).collect();
+            for (i, core) in cores.iter_mut().enumerate() {
+                if i % 2 == 1 {
+                    core.set_executor(Arc::new(InlineExecutor::new()), -1);
+                }
+            }
+            let results: Vec<Arc<AtomicUsize>> =
+                (0..1000).map(|_| Arc::new(AtomicUsize::new(0))).collect();
+            let producer = unsafe {
+                spawn_unsafe(|| {
+                    for (i, core) in cores.iter().enumerate() {
+                        core.set_result(Try::new_value(i)).unwrap();
+                    }
+                })
+            };
+            for (core, result) in cores.iter().zip(results.iter()) {
+                let ready = ready.clone();
+                core.set_ready_callback(Box::new(move || {
+                    ready.fetch_add(1, Ordering::SeqCst);
+                }));
+                let result = result.clone();
+                core.set_callback(move |try| {
+                        // Zero means the callback didn't run
+                        result.fetch_add(try.value().unwrap() + 1, Ordering::SeqCst);
+                    })
+                    .unwrap();
+            }
+            producer.join().unwrap();
+            for (i, (core, result)) in cores.iter().zip(results.iter()).enumerate() {
+                assert_eq!(core.get_state(), State::Done);
+                assert_eq!(result.load(Ordering::SeqCst), i + 1);
+            }
+            // Each ready callback ran once, whichever side got there first
+            assert_eq!(ready.load(Ordering::SeqCst), (batch + 1) * cores.len());
+        }
+    }
+
     #[test]
     fn set_result_once() {
         let core: Core<usize> = Core::new();
@@ -621,6 +1066,87 @@ mod tests {
         assert_eq!(COUNTER.load(Ordering::SeqCst), 1);
     }
 
+    #[test]
+    fn context_propagates_to_callback() {
+        static TRACE_ID: AtomicUsize = AtomicUsize::new(0);
+        let core: Core<usize> = Core::new();
+        let prev = RequestContext::create();
+        RequestContext::save_context().set_context_data("trace_id", 1234 as usize);
+        core.set_callback(|_| {
+            let ctxt = RequestContext::save_context();
+            TRACE_ID.store(ctxt.get_context_data::<usize>("trace_id").unwrap(),
+                           Ordering::SeqCst);
+        });
+        RequestContext::set_context(prev);
+        // Fulfill from another thread, which has its own context
+        let child = unsafe {
+            spawn_unsafe(|| {
+                core.set_result(Try::new_value(1));
+                // The fulfilling thread's context is restored afterwards
+                assert!(!RequestContext::save_context().has_context_data("trace_id"));
+            })
+        };
+        child.join().unwrap();
+        assert_eq!(TRACE_ID.load(Ordering::SeqCst), 1234);
+    }
+
+    #[test]
+    fn no_executor_runs_inline() {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let core: Core<usize> = Core::new();
+        assert!(core.get_executor().is_none());
+        core.set_callback(|try| {
+            COUNTER.fetch_add(try.value().unwrap(), Ordering::SeqCst);
+        });
+        assert_eq!(COUNTER.load(Ordering::SeqCst), 0);
+        core.set_result(Try::new_value(3));
+        // Ran before set_result returned
+        assert_eq!(COUNTER.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn inline_executor_runs_inline() {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let mut core: Core<usize> = Core::new();
+        core.set_executor(Arc::new(InlineExecutor::new()), -1);
+        core.set_callback(|try| {
+            COUNTER.fetch_add(try.value().unwrap(), Ordering::SeqCst);
+        });
+        core.set_result(Try::new_value(3));
+        assert_eq!(COUNTER.load(Ordering::SeqCst), 3);
+    }
+
+    #[cfg(not(feature = "core-assertions"))]
+    #[bench]
+    fn fsm_update_state_bench(b: &mut Bencher) {
+        let fsm = FSM::new(State::Start);
+        b.iter(|| {
+            fsm.update_state(State::Start, State::OnlyResult, || {});
+            fsm.update_state(State::OnlyResult, State::Start, || {});
+        });
+    }
+
+    /// The same transitions guarded by a separate MicroSpinLock, as the FSM
+    /// used to be, for comparison with fsm_update_state_bench
+    #[bench]
+    fn fsm_update_state_spinlock_bench(b: &mut Bencher) {
+        let lock = MicroSpinLock::new();
+        let state = AtomicUsize::new(State::Start.to_u8() as usize);
+        let transition = |from: State, to: State| {
+            if !lock.try_lock() {
+                lock.lock();
+            }
+            if state.load(Ordering::Acquire) == from.to_u8() as usize {
+                state.store(to.to_u8() as usize, Ordering::Release);
+            }
+            lock.unlock();
+        };
+        b.iter(|| {
+            transition(State::Start, State::OnlyResult);
+            transition(State::OnlyResult, State::Start);
+        });
+    }
+
     #[bench]
     fn set_callback_then_set_result_bench(b: &mut Bencher) {
         static COUNTER: AtomicUsize = AtomicUsize::new(0);
@@ -632,4 +1158,28 @@ mod tests {
             core.set_result(Try::new_value(1));
         });
     }
+
+    /// A Promise thread and a Future thread race to fulfil and consume the
+    /// same batch of Cores, which is where false sharing between their
+    /// halves of a Core shows up.
+    #[bench]
+    fn set_result_set_callback_ping_pong_bench(b: &mut Bencher) {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        b.iter(|| {
+            let cores: Vec<Core<usize>> = (0..256).map(|_| Core::new()).collect();
+            let producer = unsafe {
+                spawn_unsafe(|| {
+                    for core in &cores {
+                        core.set_result(Try::new_value(1));
+                    }
+                })
+            };
+            for core in &cores {
+                core.set_callback(|_| {
+                    COUNTER.fetch_add(1, Ordering::Relaxed);
+                });
+            }
+            let _res = producer.join();
+        });
+    }
 }
@@ -1 +1,2 @@
+pub mod cachepadded;
 pub mod core;
@@ -0,0 +1,253 @@
+use std::error;
+use std::fmt;
+use std::io::{Error, ErrorKind};
+
+/// The ways the futures library itself can fail a Promise or Future
+/// operation. These are handed out wrapped in an io::Error, so existing
+/// callers keep working, and can be recovered with FutureError::from_io.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FutureError {
+    /// The Promise or Future has been moved from or detached from its core
+    NoState,
+    /// get_future has already been called on this Promise
+    AlreadyRetrieved,
+    /// The Promise has already been fulfilled
+    AlreadySatisfied,
+    /// The Promise was dropped without being fulfilled
+    BrokenPromise,
+    /// The result was asked for before it was set
+    NotReady,
+    /// A deadline passed before the result was set
+    TimedOut,
+}
+
+impl FutureError {
+    /// The ErrorKind used when wrapping this error in an io::Error.
+    pub fn kind(&self) -> ErrorKind {
+        match *self {
+            FutureError::TimedOut => ErrorKind::TimedOut,
+            _ => ErrorKind::Other,
+        }
+    }
+
+    /// Returns the FutureError carried by `err`, or None if `err` came from
+    /// somewhere else. A CallbackPanicked error counts as BrokenPromise.
+    pub fn from_io(err: &Error) -> Option<FutureError> {
+        let inner = match err.get_ref() {
+            Some(inner) => inner,
+            None => return None,
+        };
+        if inner.is::<CallbackPanicked>() {
+            return Some(FutureError::BrokenPromise);
+        }
+        return inner.downcast_ref::<FutureError>().cloned();
+    }
+}
+
+impl fmt::Display for FutureError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", error::Error::description(self))
+    }
+}
+
+impl error::Error for FutureError {
+    fn description(&self) -> &str {
+        match *self {
+            FutureError::NoState => "No state",
+            FutureError::AlreadyRetrieved => "Promise already retrieved",
+            FutureError::AlreadySatisfied => "Promise already satisfied",
+            FutureError::BrokenPromise => "Broken Promise",
+            FutureError::NotReady => "Future not ready",
+            FutureError::TimedOut => "Timed out",
+        }
+    }
+}
+
+impl From<FutureError> for Error {
+    fn from(err: FutureError) -> Error {
+        return Error::new(err.kind(), err);
+    }
+}
+
+/// io::Error isn't Clone, so this is how one error is handed to several
+/// futures. The errors this library creates keep their payload, so
+/// FutureError::from_io and ChainError::from_io still work on the copy;
+/// any other payload is replaced by its message.
+pub fn clone_error(err: &Error) -> Error {
+    if let Some(code) = err.raw_os_error() {
+        return Error::from_raw_os_error(code);
+    }
+    let inner = match err.get_ref() {
+        Some(inner) => inner,
+        None => return Error::from(err.kind()),
+    };
+    if let Some(future_err) = inner.downcast_ref::<FutureError>() {
+        return Error::new(err.kind(), *future_err);
+    }
+    if let Some(panicked) = inner.downcast_ref::<CallbackPanicked>() {
+        return Error::new(err.kind(),
+                          CallbackPanicked { message: panicked.message.clone() });
+    }
+    if let Some(chain) = inner.downcast_ref::<ChainError>() {
+        return Error::new(err.kind(),
+                          ChainError {
+                              origin: chain.origin,
+                              cause: clone_error(&chain.cause),
+                          });
+    }
+    return Error::new(err.kind(), err.to_string());
+}
+
+/// The error a panicking callback leaves in its downstream future, carrying
+/// the panic message. Like a Promise dropped by a panicking producer, the
+/// callback will never fulfil the future, so FutureError::from_io reports
+/// it as BrokenPromise.
+#[derive(Debug)]
+pub struct CallbackPanicked {
+    message: String,
+}
+
+impl CallbackPanicked {
+    /// Wrap the panic's `message` in an io::Error.
+    pub fn new(message: String) -> Error {
+        return Error::new(ErrorKind::Other, CallbackPanicked { message: message });
+    }
+
+    pub fn message(&self) -> &str {
+        return &self.message;
+    }
+}
+
+impl fmt::Display for CallbackPanicked {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl error::Error for CallbackPanicked {
+    fn description(&self) -> &str {
+        "Callback panicked"
+    }
+}
+
+/// Which side of a Future::then_traced a failure came from.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ErrorOrigin {
+    /// The future then_traced was called on failed
+    Source,
+    /// The callback, or the future it returned, failed
+    Continuation,
+}
+
+/// An error tagged with where in a then_traced chain it came from. It is
+/// wrapped in an io::Error of the original kind, and can be recovered with
+/// ChainError::from_io.
+#[derive(Debug)]
+pub struct ChainError {
+    origin: ErrorOrigin,
+    cause: Error,
+}
+
+impl ChainError {
+    /// Wrap `cause` in an io::Error of the same kind, tagged with `origin`.
+    pub fn wrap(origin: ErrorOrigin, cause: Error) -> Error {
+        let kind = cause.kind();
+        return Error::new(kind,
+                          ChainError {
+                              origin: origin,
+                              cause: cause,
+                          });
+    }
+
+    /// Returns the ChainError carried by `err`, if it has one.
+    pub fn from_io(err: &Error) -> Option<&ChainError> {
+        return err.get_ref().and_then(|inner| inner.downcast_ref::<ChainError>());
+    }
+
+    pub fn origin(&self) -> ErrorOrigin {
+        return self.origin;
+    }
+
+    /// The error as it was before being tagged.
+    pub fn cause(&self) -> &Error {
+        return &self.cause;
+    }
+}
+
+impl fmt::Display for ChainError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.origin {
+            ErrorOrigin::Source => write!(f, "source failed: {}", self.cause),
+            ErrorOrigin::Continuation => write!(f, "continuation failed: {}", self.cause),
+        }
+    }
+}
+
+impl error::Error for ChainError {
+    fn description(&self) -> &str {
+        match self.origin {
+            ErrorOrigin::Source => "source failed",
+            ErrorOrigin::Continuation => "continuation failed",
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        Some(&self.cause)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::io::{Error, ErrorKind};
+
+    use promise::Promise;
+    use super::{CallbackPanicked, ChainError, ErrorOrigin, FutureError, clone_error};
+
+    #[test]
+    fn test_io_round_trip() {
+        let err: Error = FutureError::TimedOut.into();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+        assert_eq!(err.to_string(), "Timed out");
+        assert_eq!(FutureError::from_io(&err), Some(FutureError::TimedOut));
+
+        let other = Error::new(ErrorKind::TimedOut, "Timed out");
+        assert_eq!(FutureError::from_io(&other), None);
+    }
+
+    #[test]
+    fn test_broken_promise() {
+        let mut p = Promise::<usize>::new();
+        let mut f = p.get_future().unwrap();
+        drop(p);
+        let err = f.value().unwrap_err();
+        match FutureError::from_io(&err) {
+            Some(FutureError::BrokenPromise) => {}
+            other => panic!("expected BrokenPromise, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_clone_error() {
+        let err = clone_error(&FutureError::TimedOut.into());
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+        assert_eq!(FutureError::from_io(&err), Some(FutureError::TimedOut));
+
+        let err = clone_error(&CallbackPanicked::new("boom".to_string()));
+        assert_eq!(err.to_string(), "boom");
+        assert_eq!(FutureError::from_io(&err), Some(FutureError::BrokenPromise));
+
+        let chained = ChainError::wrap(ErrorOrigin::Source, FutureError::NotReady.into());
+        let err = clone_error(&chained);
+        let chain = ChainError::from_io(&err).unwrap();
+        assert_eq!(chain.origin(), ErrorOrigin::Source);
+        assert_eq!(FutureError::from_io(chain.cause()), Some(FutureError::NotReady));
+
+        let err = clone_error(&Error::from_raw_os_error(2));
+        assert_eq!(err.raw_os_error(), Some(2));
+
+        let err = clone_error(&Error::new(ErrorKind::NotFound, "missing"));
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        assert_eq!(err.to_string(), "missing");
+    }
+}
@@ -1,39 +1,108 @@
 use std::boxed::{Box, FnBox};
-use std::cell::UnsafeCell;
+use std::cell::{Cell, UnsafeCell};
+use std::cmp::Ordering as CmpOrdering;
+use std::collections::BinaryHeap;
 use std::collections::vec_deque::VecDeque;
-use std::mem;
+use std::os::unix::thread::JoinHandleExt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, Once, ONCE_INIT};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use libc::pthread_self;
+
+use request::RequestContext;
+use scopeguard::ScopeGuard;
 
 /// An Executor accepts units of work with add(), which must be
 /// threadsafe.
 pub trait Executor {
     /// Enqueue a function to executed by this executor. This and all
     /// variants must be threadsafe.
-    /// We ensure that the work to be done will outlive the Executor
-    /// ensuring that the work will be alive by the time the Executor
-    /// can execute it and consume its lifetime.
-    fn add<'a, 'b>(&'a self, work: Box<FnBox() + Send + 'b>) -> () where 'b: 'a;
+    /// The work must be 'static, since executors are free to run it after
+    /// add returns and on another thread.
+    fn add(&self, work: Box<FnBox() + Send>) -> ();
+
+    /// Like add, but executors that can't accept the work right now (e.g.
+    /// because they are full or shut down) hand it back instead.
+    fn try_add(&self, work: Box<FnBox() + Send>) -> Result<(), Box<FnBox() + Send>> {
+        self.add(work);
+        return Ok(());
+    }
+
+    /// Add several pieces of work at once, in order. Executors with a
+    /// locked queue override this to take the lock once for the whole batch.
+    fn add_batch(&self, work: Vec<Box<FnBox() + Send>>) -> () {
+        for item in work {
+            self.add(item);
+        }
+    }
 
     fn get_num_priorities(&self) -> u8 {
         return 1;
     }
+
+    /// How much work has been added but not yet run, e.g. for producers
+    /// that want to back off when an executor is saturated. Executors that
+    /// never queue work report 0.
+    fn num_pending(&self) -> usize {
+        return 0;
+    }
+}
+
+/// Wrap `work` so it runs with the RequestContext current when it was
+/// added, wherever and whenever it ends up running. Executors that queue
+/// work call this in add, as unlike callbacks going through a Core, tasks
+/// added directly have nobody else to carry the context for them.
+pub fn with_context(work: Box<FnBox() + Send>) -> Box<FnBox() + Send> {
+    let ctxt = RequestContext::save_context();
+    return Box::new(move || {
+        let prev = RequestContext::set_context(ctxt);
+        let _guard = ScopeGuard::new(move || {
+            RequestContext::set_context(prev);
+        });
+        work.call_box(());
+    });
+}
+
+pub struct InlineExecutor {
+    max_depth: Option<usize>,
 }
 
-pub struct InlineExecutor;
+// How many InlineExecutor tasks are running nested on this thread.
+thread_local!(static INLINE_DEPTH: Cell<usize> = Cell::new(0));
 
 /// When work is "queued", execute it immediately inline.
 /// Usually when you think you want this, you actually want a
 /// QueuedImmediateExecutor.
 impl InlineExecutor {
     pub const fn new() -> InlineExecutor {
-        InlineExecutor
+        InlineExecutor { max_depth: None }
+    }
+
+    /// Panics rather than run work nested more than `max_depth` inline tasks
+    /// deep on one thread, so that a runaway chain fails with a clear message
+    /// instead of overflowing the stack.
+    pub const fn with_max_depth(max_depth: usize) -> InlineExecutor {
+        InlineExecutor { max_depth: Some(max_depth) }
     }
 }
 
 impl Executor for InlineExecutor {
-    fn add<'a, 'b>(&'a self, work: Box<FnBox() + Send + 'b>) -> ()
-        where 'b: 'a
-    {
-        work.call_box(());
+    fn add(&self, work: Box<FnBox() + Send>) -> () {
+        INLINE_DEPTH.with(|depth| {
+            if let Some(max_depth) = self.max_depth {
+                if depth.get() >= max_depth {
+                    panic!("InlineExecutor recursion depth exceeded {}, consider a \
+                            QueuedImmediateExecutor",
+                           max_depth);
+                }
+            }
+            depth.set(depth.get() + 1);
+            let _guard = ScopeGuard::new(|| depth.set(depth.get() - 1));
+            work.call_box(());
+        });
     }
 }
 
@@ -42,88 +111,1517 @@ fn test_inline_executor() {
     use std::sync::atomic::{AtomicUsize, Ordering};
     use std::mem;
 
+    static CNTR: AtomicUsize = AtomicUsize::new(0);
     let inline = InlineExecutor::new();
-    let cntr = AtomicUsize::new(0);
     inline.add(Box::new(|| {
-        cntr.fetch_add(1, Ordering::AcqRel);
+        CNTR.fetch_add(1, Ordering::AcqRel);
     }));
-    let val = cntr.load(Ordering::Acquire);
+    let val = CNTR.load(Ordering::Acquire);
     assert_eq!(val, 1);
 }
 
+#[cfg(test)]
+static GUARDED_INLINE: InlineExecutor = InlineExecutor::with_max_depth(8);
+
+#[cfg(test)]
+fn add_nested_inline(remaining: usize, ran: &'static AtomicUsize) {
+    if remaining == 0 {
+        return;
+    }
+    GUARDED_INLINE.add(Box::new(move || {
+        ran.fetch_add(1, Ordering::SeqCst);
+        add_nested_inline(remaining - 1, ran);
+    }));
+}
+
+#[test]
+fn test_inline_executor_max_depth() {
+    static RAN: AtomicUsize = AtomicUsize::new(0);
+    // Up to the limit is fine, and the depth unwinds afterwards
+    add_nested_inline(8, &RAN);
+    add_nested_inline(8, &RAN);
+    assert_eq!(RAN.load(Ordering::SeqCst), 16);
+}
+
+#[test]
+#[should_panic(expected = "InlineExecutor recursion depth exceeded 8")]
+fn test_inline_executor_max_depth_exceeded() {
+    static RAN: AtomicUsize = AtomicUsize::new(0);
+    add_nested_inline(1000, &RAN);
+}
+
 thread_local!(static QUEUE: UnsafeCell<VecDeque<Box<FnBox() + Send>>>
               = UnsafeCell::new(VecDeque::new()));
 
+/// Whether this thread is already draining QUEUE further up the stack, in
+/// which case new work is queued rather than run.
+thread_local!(static DRAINING: Cell<bool> = Cell::new(false));
+
 /// Runs inline like InlineExecutor, but with a queue so that any tasks added
 /// to this executor by one of its own callbacks will be queued instead of
 /// executed inline (nested). This is usually better behavior than Inline.
-pub struct QueuedImmediateExecutor;
+pub struct QueuedImmediateExecutor {
+    max_depth: Option<usize>,
+}
 
 impl QueuedImmediateExecutor {
     pub fn new() -> QueuedImmediateExecutor {
-        return QueuedImmediateExecutor;
+        return QueuedImmediateExecutor { max_depth: None };
+    }
+
+    /// Refuses work once `max_depth` tasks are already queued on this
+    /// thread, so a task that keeps adding work fails instead of growing the
+    /// queue without bound. try_add hands the work back and add panics.
+    pub fn with_max_depth(max_depth: usize) -> QueuedImmediateExecutor {
+        return QueuedImmediateExecutor { max_depth: Some(max_depth) };
+    }
+
+    /// Whether this thread is running work from a QueuedImmediateExecutor,
+    /// in which case anything added now is queued to run after the current
+    /// task rather than run inline.
+    pub fn is_draining() -> bool {
+        return DRAINING.with(|draining| draining.get());
     }
 }
 
 impl Executor for QueuedImmediateExecutor {
-    fn add<'a, 'b>(&'a self, work: Box<FnBox() + Send + 'b>) -> ()
-        where 'b: 'a
-    {
+    fn add(&self, work: Box<FnBox() + Send>) -> () {
+        if self.try_add(work).is_err() {
+            panic!("QueuedImmediateExecutor queue is over its maximum depth");
+        }
+    }
+
+    fn try_add(&self, work: Box<FnBox() + Send>) -> Result<(), Box<FnBox() + Send>> {
+        if let Some(max_depth) = self.max_depth {
+            if self.num_pending() >= max_depth {
+                return Err(work);
+            }
+        }
         QUEUE.with(|queue| {
             unsafe {
-                let queue: *mut VecDeque<Box<FnBox() + Send + 'static>> = queue.get();
-                if (*queue).is_empty() {
-                    // We have to transmute the work to pretend it has
-                    // 'static lifetime so we can stuff it into the thread local
-                    // queue. However this is reasonably safe since the work is
-                    // guaranteed to live longer than the Executor and in most
-                    // cases the Executor should be fully consuming the work.
-                    // Only edge case that comes to mind is if the work/closure
-                    // had some RAII items it was using to signal behavior, but
-                    // that seems okay to break.
-                    // TODO(ptc) see if there's a better way around this transmute
-                    (*queue).push_back(mem::transmute(work));
-                    while !(*queue).is_empty() {
-                        // TODO(ptc) Since we have to own the Box<FnBox> in order
-                        // to call it we have to pop it off the queue, but that
-                        // changes the queue's size so that future calls to add will
-                        // execute immediately, so we use a place holder we remove
-                        // later.
-                        // Figure out a better way to do this so we
-                        // don't need a placeholder
-                        let fnbox = (*queue).pop_front().unwrap();
-                        (*queue).push_front(Box::new(|| {
-                            // placeholder
-                        }));
-                        fnbox.call_box(());
-                        let _discarded_placeholder = (*queue).pop_front();
-                    }
-                } else {
-                    (*queue).push_back(mem::transmute(work));
-                }
+                let queue: *mut VecDeque<Box<FnBox() + Send>> = queue.get();
+                (*queue).push_back(with_context(work));
             }
         });
+        drain_queue();
+        return Ok(());
+    }
+
+    /// Only counts work queued on the calling thread
+    fn num_pending(&self) -> usize {
+        QUEUE.with(|queue| unsafe { (*queue.get()).len() })
     }
 }
 
+/// Run the work queued on this thread, including any queued along the way,
+/// unless this thread is already draining further up the stack.
+///
+/// Every add ends here and a panicking task clears what is left, so the
+/// queue is empty again once the outermost add returns. That's why
+/// QueuedImmediateExecutor doesn't need to drain anything when dropped.
+fn drain_queue() {
+    QUEUE.with(|queue| {
+        DRAINING.with(|draining| {
+            if draining.get() {
+                return;
+            }
+            draining.set(true);
+            let _draining_guard = ScopeGuard::new(|| draining.set(false));
+            let queue = queue.get();
+            // If a task panics, drop whatever is still queued so
+            // the next add on this thread starts from an empty queue.
+            let _clear_guard = ScopeGuard::on_failure(|| unsafe { (*queue).clear() });
+            while let Some(fnbox) = unsafe { (*queue).pop_front() } {
+                fnbox.call_box(());
+            }
+        });
+    });
+}
+
 #[test]
 fn test_queued_executor() {
     use std::sync::atomic::{AtomicUsize, Ordering};
     use std::mem;
 
+    static CNTR: AtomicUsize = AtomicUsize::new(0);
     let queued = QueuedImmediateExecutor::new();
-    let cntr = AtomicUsize::new(0);
     queued.add(Box::new(|| {
-        queued.add(Box::new(|| {
+        // Any QueuedImmediateExecutor queues behind the running task
+        QueuedImmediateExecutor::new().add(Box::new(|| {
             // Should happen last
-            let val = cntr.load(Ordering::Acquire);
+            let val = CNTR.load(Ordering::Acquire);
             assert_eq!(val, 1);
-            cntr.fetch_add(1, Ordering::AcqRel);
+            CNTR.fetch_add(1, Ordering::AcqRel);
         }));
-        let val = cntr.load(Ordering::Acquire);
+        let val = CNTR.load(Ordering::Acquire);
         assert_eq!(val, 0);
-        cntr.fetch_add(1, Ordering::AcqRel);
+        CNTR.fetch_add(1, Ordering::AcqRel);
     }));
-    let val = cntr.load(Ordering::Acquire);
+    let val = CNTR.load(Ordering::Acquire);
     assert_eq!(val, 2);
 }
+
+#[test]
+fn test_queued_executor_is_draining() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static RAN: AtomicUsize = AtomicUsize::new(0);
+    let queued = QueuedImmediateExecutor::new();
+    assert!(!QueuedImmediateExecutor::is_draining());
+    queued.add(Box::new(|| {
+        assert!(QueuedImmediateExecutor::is_draining());
+        QueuedImmediateExecutor::new().add(Box::new(|| {
+            assert!(QueuedImmediateExecutor::is_draining());
+            RAN.fetch_add(1, Ordering::AcqRel);
+        }));
+        RAN.fetch_add(1, Ordering::AcqRel);
+    }));
+    assert_eq!(RAN.load(Ordering::Acquire), 2);
+    assert!(!QueuedImmediateExecutor::is_draining());
+}
+
+#[test]
+fn test_queued_executor_no_placeholder() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static RAN: AtomicUsize = AtomicUsize::new(0);
+    let queued = QueuedImmediateExecutor::new();
+    queued.add(Box::new(|| {
+        // The running task has been popped, and nothing stands in for it
+        let queued = QueuedImmediateExecutor::new();
+        assert_eq!(queued.num_pending(), 0);
+        queued.add(Box::new(|| {
+            assert_eq!(QueuedImmediateExecutor::new().num_pending(), 0);
+            RAN.fetch_add(1, Ordering::AcqRel);
+        }));
+        assert_eq!(queued.num_pending(), 1);
+    }));
+    assert_eq!(RAN.load(Ordering::Acquire), 1);
+    assert_eq!(queued.num_pending(), 0);
+}
+
+#[test]
+fn test_queued_executor_max_depth() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static RAN: AtomicUsize = AtomicUsize::new(0);
+    static REJECTED: AtomicUsize = AtomicUsize::new(0);
+    let queued = QueuedImmediateExecutor::with_max_depth(4);
+    queued.add(Box::new(|| {
+        // Everything added here waits behind this task, so only the first
+        // four fit
+        let queued = QueuedImmediateExecutor::with_max_depth(4);
+        for _ in 0..10 {
+            let res = queued.try_add(Box::new(|| {
+                RAN.fetch_add(1, Ordering::AcqRel);
+            }));
+            if res.is_err() {
+                REJECTED.fetch_add(1, Ordering::AcqRel);
+            }
+        }
+    }));
+    assert_eq!(RAN.load(Ordering::Acquire), 4);
+    assert_eq!(REJECTED.load(Ordering::Acquire), 6);
+    assert_eq!(queued.num_pending(), 0);
+}
+
+#[test]
+#[should_panic(expected = "QueuedImmediateExecutor queue is over its maximum depth")]
+fn test_queued_executor_max_depth_add() {
+    let queued = QueuedImmediateExecutor::with_max_depth(1);
+    queued.add(Box::new(|| {
+        let queued = QueuedImmediateExecutor::with_max_depth(1);
+        queued.add(Box::new(|| {}));
+        queued.add(Box::new(|| {}));
+    }));
+}
+
+#[test]
+fn test_queued_executor_panic() {
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static CNTR: AtomicUsize = AtomicUsize::new(0);
+    let queued = QueuedImmediateExecutor::new();
+    let res = panic::catch_unwind(AssertUnwindSafe(|| {
+        queued.add(Box::new(|| {
+            QueuedImmediateExecutor::new().add(Box::new(|| {
+                // Dropped along with the panicking task
+                CNTR.fetch_add(10, Ordering::AcqRel);
+            }));
+            panic!("task failed");
+        }));
+    }));
+    assert!(res.is_err());
+    queued.add(Box::new(|| {
+        CNTR.fetch_add(1, Ordering::AcqRel);
+    }));
+    // Still runs inline rather than being stuck behind a placeholder
+    let val = CNTR.load(Ordering::Acquire);
+    assert_eq!(val, 1);
+}
+
+struct QueuedState {
+    queue: VecDeque<Box<FnBox() + Send>>,
+    draining: bool,
+}
+
+/// Like QueuedImmediateExecutor, but the queue is shared by every thread
+/// adding to this executor instead of being thread local, so work is run
+/// in the order it was added no matter which thread added it. Work is run
+/// inline by whichever thread finds the queue isn't being drained.
+#[derive(Clone)]
+pub struct QueuedExecutor {
+    state: Arc<Mutex<QueuedState>>,
+}
+
+impl QueuedExecutor {
+    pub fn new() -> QueuedExecutor {
+        QueuedExecutor {
+            state: Arc::new(Mutex::new(QueuedState {
+                queue: VecDeque::new(),
+                draining: false,
+            })),
+        }
+    }
+
+    /// Run queued work until the queue is empty. Only called by the thread
+    /// that set `draining`.
+    fn drain(&self) {
+        // The lock isn't held while work runs, so a panicking task can't
+        // poison it. Drop what's left so the next add starts afresh.
+        let _guard = ScopeGuard::on_failure(|| {
+            let mut state = self.state.lock().unwrap();
+            state.queue.clear();
+            state.draining = false;
+        });
+        loop {
+            let work = {
+                let mut state = self.state.lock().unwrap();
+                match state.queue.pop_front() {
+                    Some(work) => work,
+                    None => {
+                        state.draining = false;
+                        return;
+                    }
+                }
+            };
+            work.call_box(());
+        }
+    }
+}
+
+impl Executor for QueuedExecutor {
+    fn add(&self, work: Box<FnBox() + Send>) -> () {
+        {
+            let mut state = self.state.lock().unwrap();
+            state.queue.push_back(with_context(work));
+            if state.draining {
+                return;
+            }
+            state.draining = true;
+        }
+        self.drain();
+    }
+
+    fn add_batch(&self, work: Vec<Box<FnBox() + Send>>) -> () {
+        {
+            let mut state = self.state.lock().unwrap();
+            for item in work {
+                state.queue.push_back(with_context(item));
+            }
+            if state.draining {
+                return;
+            }
+            state.draining = true;
+        }
+        self.drain();
+    }
+
+    fn num_pending(&self) -> usize {
+        return self.state.lock().unwrap().queue.len();
+    }
+}
+
+/// Queues work until run() is called, giving complete control over when
+/// work happens, which is mostly useful for tests.
+pub struct ManualExecutor {
+    queue: Mutex<VecDeque<Box<FnBox() + Send>>>,
+}
+
+impl ManualExecutor {
+    pub fn new() -> ManualExecutor {
+        ManualExecutor { queue: Mutex::new(VecDeque::new()) }
+    }
+
+    /// Run the work queued so far, not including any work that work adds,
+    /// so this can't chase an ever growing queue.
+    /// Returns the number of tasks run
+    pub fn run(&self) -> usize {
+        let count = self.queue.lock().unwrap().len();
+        for _ in 0..count {
+            // Don't hold the lock while running, work may add more work
+            let work = self.queue.lock().unwrap().pop_front();
+            if let Some(work) = work {
+                work.call_box(());
+            }
+        }
+        return count;
+    }
+}
+
+impl Executor for ManualExecutor {
+    fn add(&self, work: Box<FnBox() + Send>) -> () {
+        self.queue.lock().unwrap().push_back(with_context(work));
+    }
+
+    fn add_batch(&self, work: Vec<Box<FnBox() + Send>>) -> () {
+        let mut queue = self.queue.lock().unwrap();
+        for item in work {
+            queue.push_back(with_context(item));
+        }
+    }
+
+    fn num_pending(&self) -> usize {
+        return self.queue.lock().unwrap().len();
+    }
+}
+
+/// What BoundedExecutor::try_add does when the executor is full.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum BoundedMode {
+    /// Wait until a slot frees up
+    Block,
+    /// Hand the work straight back
+    Reject,
+}
+
+struct BoundedState {
+    in_flight: AtomicUsize,
+    lock: Mutex<()>,
+    slot_freed: Condvar,
+}
+
+impl BoundedState {
+    /// Returns true if it took a slot, false if there were none free
+    fn try_acquire(&self, capacity: usize) -> bool {
+        let mut cur = self.in_flight.load(Ordering::Acquire);
+        while cur < capacity {
+            match self.in_flight
+                .compare_exchange(cur, cur + 1, Ordering::AcqRel, Ordering::Acquire) {
+                Ok(_) => return true,
+                Err(actual) => cur = actual,
+            }
+        }
+        return false;
+    }
+
+    fn acquire(&self, capacity: usize) {
+        if self.try_acquire(capacity) {
+            return;
+        }
+        let mut guard = self.lock.lock().unwrap();
+        // Check again under the lock so we can't miss a release's notify
+        while !self.try_acquire(capacity) {
+            guard = self.slot_freed.wait(guard).unwrap();
+        }
+    }
+
+    fn release(&self) {
+        self.in_flight.fetch_sub(1, Ordering::AcqRel);
+        let _guard = self.lock.lock().unwrap();
+        self.slot_freed.notify_one();
+    }
+}
+
+/// Wraps another executor, limiting how many tasks may be added to it but
+/// not yet finished. A task frees its slot when it finishes running, which
+/// admits the next waiting task. Since add can't hand work back it always
+/// waits for a free slot, try_add waits or rejects depending on the mode.
+pub struct BoundedExecutor {
+    inner: Arc<Executor + Send + Sync>,
+    capacity: usize,
+    mode: BoundedMode,
+    state: Arc<BoundedState>,
+}
+
+impl BoundedExecutor {
+    pub fn new(inner: Arc<Executor + Send + Sync>,
+               capacity: usize,
+               mode: BoundedMode)
+               -> BoundedExecutor {
+        BoundedExecutor {
+            inner: inner,
+            capacity: capacity,
+            mode: mode,
+            state: Arc::new(BoundedState {
+                in_flight: AtomicUsize::new(0),
+                lock: Mutex::new(()),
+                slot_freed: Condvar::new(),
+            }),
+        }
+    }
+
+    /// Hand work that already holds a slot to the inner executor
+    fn add_acquired(&self, work: Box<FnBox() + Send>) {
+        let state = self.state.clone();
+        self.inner.add(Box::new(move || {
+            let _guard = ScopeGuard::new(|| state.release());
+            work.call_box(());
+        }));
+    }
+}
+
+impl Executor for BoundedExecutor {
+    fn add(&self, work: Box<FnBox() + Send>) -> () {
+        self.state.acquire(self.capacity);
+        self.add_acquired(work);
+    }
+
+    fn try_add(&self, work: Box<FnBox() + Send>) -> Result<(), Box<FnBox() + Send>> {
+        match self.mode {
+            BoundedMode::Block => self.state.acquire(self.capacity),
+            BoundedMode::Reject => {
+                if !self.state.try_acquire(self.capacity) {
+                    return Err(work);
+                }
+            }
+        }
+        self.add_acquired(work);
+        return Ok(());
+    }
+
+    fn num_pending(&self) -> usize {
+        self.inner.num_pending()
+    }
+}
+
+#[test]
+fn test_bounded_executor_block() {
+    use std::sync::atomic::AtomicBool;
+    use std::thread;
+    use std::time::Duration;
+    use microspinlock::spawn_unsafe;
+
+    let manual = Arc::new(ManualExecutor::new());
+    let bounded = BoundedExecutor::new(manual.clone(), 4, BoundedMode::Block);
+    for _ in 0..4 {
+        bounded.add(Box::new(|| {}));
+    }
+    let added = AtomicBool::new(false);
+    let child = unsafe {
+        spawn_unsafe(|| {
+            bounded.add(Box::new(|| {}));
+            added.store(true, Ordering::Release);
+        })
+    };
+    thread::sleep(Duration::from_millis(50));
+    // Parked until a slot frees up
+    assert!(!added.load(Ordering::Acquire));
+    assert_eq!(manual.run(), 4);
+    child.join().unwrap();
+    assert!(added.load(Ordering::Acquire));
+    assert_eq!(bounded.num_pending(), 1);
+}
+
+#[test]
+fn test_bounded_executor_reject() {
+    let manual = Arc::new(ManualExecutor::new());
+    let bounded = BoundedExecutor::new(manual.clone(), 4, BoundedMode::Reject);
+    for _ in 0..4 {
+        assert!(bounded.try_add(Box::new(|| {})).is_ok());
+    }
+    assert!(bounded.try_add(Box::new(|| {})).is_err());
+    assert_eq!(manual.run(), 4);
+    assert!(bounded.try_add(Box::new(|| {})).is_ok());
+}
+
+struct TimerEntry {
+    deadline: Instant,
+    /// Breaks ties between equal deadlines so they run in the order added
+    seq: u64,
+    work: Box<FnBox() + Send>,
+}
+
+impl PartialEq for TimerEntry {
+    fn eq(&self, other: &TimerEntry) -> bool {
+        self.deadline == other.deadline && self.seq == other.seq
+    }
+}
+
+impl Eq for TimerEntry {}
+
+impl PartialOrd for TimerEntry {
+    fn partial_cmp(&self, other: &TimerEntry) -> Option<CmpOrdering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Reversed, so that BinaryHeap, a max-heap, pops the earliest deadline
+impl Ord for TimerEntry {
+    fn cmp(&self, other: &TimerEntry) -> CmpOrdering {
+        match other.deadline.cmp(&self.deadline) {
+            CmpOrdering::Equal => other.seq.cmp(&self.seq),
+            ord => ord,
+        }
+    }
+}
+
+struct TimerState {
+    heap: BinaryHeap<TimerEntry>,
+    next_seq: u64,
+    shutdown: bool,
+}
+
+/// Runs work after a delay on a single background thread, which sleeps
+/// until the earliest deadline. Work added with add() runs as soon as
+/// possible. Dropping the executor stops the thread, and work that isn't
+/// due yet is dropped without running.
+pub struct TimerExecutor {
+    shared: Arc<(Mutex<TimerState>, Condvar)>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl TimerExecutor {
+    pub fn new() -> TimerExecutor {
+        let shared = Arc::new((Mutex::new(TimerState {
+                                   heap: BinaryHeap::new(),
+                                   next_seq: 0,
+                                   shutdown: false,
+                               }),
+                               Condvar::new()));
+        let thread_shared = shared.clone();
+        let thread = thread::spawn(move || TimerExecutor::run(&thread_shared));
+        TimerExecutor {
+            shared: shared,
+            thread: Some(thread),
+        }
+    }
+
+    /// A process wide timer, started on first use, for timeouts that don't
+    /// need a TimerExecutor of their own. It is never shut down.
+    pub fn global() -> &'static TimerExecutor {
+        static INIT: Once = ONCE_INIT;
+        static mut GLOBAL: *const TimerExecutor = 0 as *const TimerExecutor;
+        unsafe {
+            INIT.call_once(|| {
+                GLOBAL = Box::into_raw(Box::new(TimerExecutor::new()));
+            });
+            return &*GLOBAL;
+        }
+    }
+
+    /// Run `work` once `dur` has passed.
+    pub fn schedule(&self, dur: Duration, work: Box<FnBox() + Send>) {
+        TimerExecutor::schedule_on(&self.shared, dur, work);
+    }
+
+    /// schedule, for callers such as interval that only hold the shared
+    /// state rather than the executor.
+    fn schedule_on(shared: &(Mutex<TimerState>, Condvar),
+                   dur: Duration,
+                   work: Box<FnBox() + Send>) {
+        let &(ref lock, ref cvar) = shared;
+        let mut state = lock.lock().unwrap();
+        let seq = state.next_seq;
+        state.next_seq += 1;
+        state.heap.push(TimerEntry {
+            deadline: Instant::now() + dur,
+            seq: seq,
+            work: with_context(work),
+        });
+        // The new entry may be due before whatever the thread sleeps on
+        cvar.notify_one();
+    }
+
+    fn run(shared: &(Mutex<TimerState>, Condvar)) {
+        let &(ref lock, ref cvar) = shared;
+        loop {
+            let entry = {
+                let mut state = lock.lock().unwrap();
+                loop {
+                    if state.shutdown {
+                        return;
+                    }
+                    let now = Instant::now();
+                    let wait = match state.heap.peek() {
+                        Some(entry) if entry.deadline <= now => break,
+                        Some(entry) => Some(entry.deadline - now),
+                        None => None,
+                    };
+                    state = match wait {
+                        Some(dur) => cvar.wait_timeout(state, dur).unwrap().0,
+                        None => cvar.wait(state).unwrap(),
+                    };
+                }
+                state.heap.pop().unwrap()
+            };
+            run_task(entry.work);
+        }
+    }
+}
+
+impl Drop for TimerExecutor {
+    fn drop(&mut self) {
+        {
+            let &(ref lock, ref cvar) = &*self.shared;
+            lock.lock().unwrap().shutdown = true;
+            cvar.notify_one();
+        }
+        if let Some(thread) = self.thread.take() {
+            // Work on the timer thread may hold the last reference to the
+            // executor. The thread can't join itself, but it stops on its
+            // own once that work returns, so detach it instead.
+            if !is_current_thread(&thread) {
+                let _res = thread.join();
+            }
+        }
+    }
+}
+
+/// Whether `thread` is the one we're running on.
+fn is_current_thread(thread: &JoinHandle<()>) -> bool {
+    return thread.as_pthread_t() as usize == unsafe { pthread_self() } as usize;
+}
+
+impl Executor for TimerExecutor {
+    fn add(&self, work: Box<FnBox() + Send>) -> () {
+        self.schedule(Duration::new(0, 0), work);
+    }
+
+    fn num_pending(&self) -> usize {
+        let &(ref lock, _) = &*self.shared;
+        return lock.lock().unwrap().heap.len();
+    }
+}
+
+#[test]
+fn test_timer_executor() {
+    use std::sync::mpsc::channel;
+
+    let timer = TimerExecutor::new();
+    let (tx, rx) = channel();
+    for &delay in [30, 10, 20].iter() {
+        let tx = tx.clone();
+        timer.schedule(Duration::from_millis(delay),
+                       Box::new(move || {
+                           tx.send(delay).unwrap();
+                       }));
+    }
+    assert_eq!(timer.num_pending(), 3);
+    let fired: Vec<u64> = rx.iter().take(3).collect();
+    assert_eq!(fired, vec![10, 20, 30]);
+}
+
+struct IntervalState {
+    cancelled: AtomicBool,
+    /// The thread running a firing, if one is in progress
+    firing: Mutex<Option<usize>>,
+    /// Notified when a firing finishes
+    fired: Condvar,
+}
+
+impl IntervalState {
+    fn lock_firing(&self) -> MutexGuard<Option<usize>> {
+        // Nothing panics while this is held, and the value is fine either way
+        return self.firing.lock().unwrap_or_else(|err| err.into_inner());
+    }
+}
+
+/// Returned by interval. Dropping it cancels the interval.
+pub struct IntervalHandle {
+    state: Arc<IntervalState>,
+}
+
+impl IntervalHandle {
+    /// Stop further firings. If a firing is in progress this waits for it
+    /// to finish, and it is the last one. Called from `func` itself it
+    /// can't wait, it returns and the running firing is the last.
+    pub fn cancel(&self) {
+        let state = &self.state;
+        state.cancelled.store(true, Ordering::SeqCst);
+        let current = unsafe { pthread_self() } as usize;
+        let mut firing = state.lock_firing();
+        while firing.is_some() && *firing != Some(current) {
+            firing = state.fired.wait(firing).unwrap_or_else(|err| err.into_inner());
+        }
+    }
+}
+
+impl Drop for IntervalHandle {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
+
+/// Call `func` on `timer`'s thread every `period`, starting one period from
+/// now, until the returned handle is cancelled or dropped. Each firing
+/// schedules the next once it's done, so a slow `func` delays later ones
+/// rather than piling them up. A panic in `func` ends the interval.
+pub fn interval<F>(timer: &TimerExecutor, period: Duration, func: F) -> IntervalHandle
+    where F: Fn() + Send + 'static
+{
+    let state = Arc::new(IntervalState {
+        cancelled: AtomicBool::new(false),
+        firing: Mutex::new(None),
+        fired: Condvar::new(),
+    });
+    schedule_interval(timer.shared.clone(), period, func, state.clone());
+    return IntervalHandle { state: state };
+}
+
+fn schedule_interval<F>(shared: Arc<(Mutex<TimerState>, Condvar)>,
+                        period: Duration,
+                        func: F,
+                        state: Arc<IntervalState>)
+    where F: Fn() + Send + 'static
+{
+    let next_shared = shared.clone();
+    TimerExecutor::schedule_on(&shared,
+                               period,
+                               Box::new(move || {
+                                   {
+                                       let mut firing = state.lock_firing();
+                                       if state.cancelled.load(Ordering::SeqCst) {
+                                           return;
+                                       }
+                                       *firing = Some(unsafe { pthread_self() } as usize);
+                                   }
+                                   {
+                                       let _done = ScopeGuard::new(|| {
+                                           *state.lock_firing() = None;
+                                           state.fired.notify_all();
+                                       });
+                                       func();
+                                   }
+                                   schedule_interval(next_shared, period, func, state);
+                               }));
+}
+
+#[test]
+fn test_interval() {
+    use std::sync::atomic::AtomicUsize;
+
+    let timer = TimerExecutor::new();
+    let fired = Arc::new(AtomicUsize::new(0));
+    let handle = {
+        let fired = fired.clone();
+        interval(&timer, Duration::from_millis(10), move || {
+            fired.fetch_add(1, Ordering::AcqRel);
+        })
+    };
+    thread::sleep(Duration::from_millis(100));
+    handle.cancel();
+    let count = fired.load(Ordering::Acquire);
+    assert!(count >= 3, "only fired {} times", count);
+    thread::sleep(Duration::from_millis(50));
+    assert_eq!(fired.load(Ordering::Acquire), count);
+}
+
+#[test]
+fn test_interval_drop() {
+    use std::sync::atomic::AtomicUsize;
+
+    let timer = TimerExecutor::new();
+    let fired = Arc::new(AtomicUsize::new(0));
+    {
+        let fired = fired.clone();
+        let _handle = interval(&timer, Duration::from_millis(10), move || {
+            fired.fetch_add(1, Ordering::AcqRel);
+        });
+    }
+    thread::sleep(Duration::from_millis(50));
+    assert_eq!(fired.load(Ordering::Acquire), 0);
+}
+
+#[test]
+fn test_timer_executor_panic() {
+    use std::sync::mpsc::channel;
+
+    let timer = TimerExecutor::new();
+    let (tx, rx) = channel();
+    timer.schedule(Duration::from_millis(10), Box::new(|| panic!("timer task failed")));
+    timer.schedule(Duration::from_millis(20),
+                   Box::new(move || {
+                       tx.send(()).unwrap();
+                   }));
+    // The panic didn't stop the thread, so the later entry still fires
+    rx.recv().unwrap();
+}
+
+#[test]
+fn test_interval_cancel_from_func() {
+    use std::sync::atomic::AtomicUsize;
+
+    let timer = TimerExecutor::new();
+    let fired = Arc::new(AtomicUsize::new(0));
+    let slot: Arc<Mutex<Option<IntervalHandle>>> = Arc::new(Mutex::new(None));
+    let handle = {
+        let fired = fired.clone();
+        let slot = slot.clone();
+        interval(&timer, Duration::from_millis(10), move || {
+            fired.fetch_add(1, Ordering::AcqRel);
+            // Dropping the handle cancels too, and mustn't wait on this firing
+            if let Some(handle) = slot.lock().unwrap().take() {
+                handle.cancel();
+            }
+        })
+    };
+    *slot.lock().unwrap() = Some(handle);
+    thread::sleep(Duration::from_millis(60));
+    assert_eq!(fired.load(Ordering::Acquire), 1);
+}
+
+#[test]
+fn test_interval_panic() {
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::mpsc::channel;
+
+    let timer = TimerExecutor::new();
+    let fired = Arc::new(AtomicUsize::new(0));
+    let handle = {
+        let fired = fired.clone();
+        interval(&timer, Duration::from_millis(10), move || {
+            fired.fetch_add(1, Ordering::AcqRel);
+            panic!("interval failed");
+        })
+    };
+    thread::sleep(Duration::from_millis(60));
+    assert_eq!(fired.load(Ordering::Acquire), 1);
+    drop(handle);
+    // The timer thread is still running other work
+    let (tx, rx) = channel();
+    timer.add(Box::new(move || {
+        tx.send(()).unwrap();
+    }));
+    rx.recv().unwrap();
+}
+
+#[test]
+fn test_timer_executor_drop() {
+    use std::sync::atomic::AtomicBool;
+
+    let ran = Arc::new(AtomicBool::new(false));
+    {
+        let timer = TimerExecutor::new();
+        let ran = ran.clone();
+        timer.schedule(Duration::from_secs(60),
+                       Box::new(move || {
+                           ran.store(true, Ordering::Release);
+                       }));
+    }
+    // Dropping the executor doesn't wait for work that isn't due
+    assert!(!ran.load(Ordering::Acquire));
+}
+
+#[test]
+fn test_timer_executor_drop_on_timer_thread() {
+    use std::sync::mpsc::channel;
+
+    let timer = Arc::new(TimerExecutor::new());
+    let (go_tx, go_rx) = channel();
+    let (done_tx, done_rx) = channel();
+    let last_ref = timer.clone();
+    timer.add(Box::new(move || {
+        go_rx.recv().unwrap();
+        // Now the only reference, so the executor is dropped on its own
+        // thread
+        drop(last_ref);
+        done_tx.send(()).unwrap();
+    }));
+    drop(timer);
+    go_tx.send(()).unwrap();
+    done_rx.recv().unwrap();
+}
+
+/// Runs a task, keeping a panic in it from taking an executor's thread down
+/// with it.
+fn run_task(work: Box<FnBox() + Send>) {
+    let _res = panic::catch_unwind(AssertUnwindSafe(move || work.call_box(())));
+}
+
+struct PoolState {
+    queue: VecDeque<Box<FnBox() + Send>>,
+    shutdown: bool,
+}
+
+/// Runs work on a fixed number of threads sharing one queue. Dropping the
+/// executor waits for the work already added to run, then stops the threads.
+pub struct ThreadPoolExecutor {
+    shared: Arc<(Mutex<PoolState>, Condvar)>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl ThreadPoolExecutor {
+    pub fn new(num_threads: usize) -> ThreadPoolExecutor {
+        assert!(num_threads > 0, "ThreadPoolExecutor needs at least one thread");
+        let shared = Arc::new((Mutex::new(PoolState {
+                                   queue: VecDeque::new(),
+                                   shutdown: false,
+                               }),
+                               Condvar::new()));
+        let threads = (0..num_threads)
+            .map(|_| {
+                let shared = shared.clone();
+                thread::spawn(move || ThreadPoolExecutor::run(&shared))
+            })
+            .collect();
+        ThreadPoolExecutor {
+            shared: shared,
+            threads: threads,
+        }
+    }
+
+    fn run(shared: &(Mutex<PoolState>, Condvar)) {
+        let &(ref lock, ref cvar) = shared;
+        loop {
+            let work = {
+                let mut state = lock.lock().unwrap();
+                while state.queue.is_empty() && !state.shutdown {
+                    state = cvar.wait(state).unwrap();
+                }
+                // Only empty once shut down
+                match state.queue.pop_front() {
+                    Some(work) => work,
+                    None => return,
+                }
+            };
+            run_task(work);
+        }
+    }
+}
+
+impl Drop for ThreadPoolExecutor {
+    fn drop(&mut self) {
+        {
+            let &(ref lock, ref cvar) = &*self.shared;
+            lock.lock().unwrap().shutdown = true;
+            cvar.notify_all();
+        }
+        // Work on a pool thread may hold the last reference to the
+        // executor. That thread can't join itself, but it exits once the
+        // work returns, so it's detached instead.
+        for thread in self.threads.drain(..) {
+            if !is_current_thread(&thread) {
+                let _res = thread.join();
+            }
+        }
+    }
+}
+
+impl Executor for ThreadPoolExecutor {
+    fn add(&self, work: Box<FnBox() + Send>) -> () {
+        let &(ref lock, ref cvar) = &*self.shared;
+        lock.lock().unwrap().queue.push_back(with_context(work));
+        cvar.notify_one();
+    }
+
+    fn add_batch(&self, work: Vec<Box<FnBox() + Send>>) -> () {
+        let &(ref lock, ref cvar) = &*self.shared;
+        {
+            let mut state = lock.lock().unwrap();
+            for item in work {
+                state.queue.push_back(with_context(item));
+            }
+        }
+        cvar.notify_all();
+    }
+
+    fn num_pending(&self) -> usize {
+        let &(ref lock, _) = &*self.shared;
+        return lock.lock().unwrap().queue.len();
+    }
+}
+
+struct StealingState {
+    /// One deque per worker. Owners pop from the front and thieves from
+    /// the back, so they mostly stay out of each other's way.
+    queues: Vec<Mutex<VecDeque<Box<FnBox() + Send>>>>,
+    /// Round robin cursor for picking the deque add pushes to
+    next: AtomicUsize,
+    pending: AtomicUsize,
+    /// Workers waiting on wakeup, so add only has to notify when some are
+    sleepers: AtomicUsize,
+    /// Guards shutdown, and is held while notifying so that a worker that
+    /// saw no pending work can't miss the wakeup
+    sleep_lock: Mutex<bool>,
+    wakeup: Condvar,
+}
+
+impl StealingState {
+    /// Take work from worker `index`'s own deque, or else steal from the
+    /// back of another's.
+    fn find_work(&self, index: usize) -> Option<Box<FnBox() + Send>> {
+        if let Some(work) = self.queues[index].lock().unwrap().pop_front() {
+            return Some(work);
+        }
+        let n = self.queues.len();
+        for i in 1..n {
+            if let Some(work) = self.queues[(index + i) % n].lock().unwrap().pop_back() {
+                return Some(work);
+            }
+        }
+        return None;
+    }
+}
+
+/// Like ThreadPoolExecutor, but each thread has its own queue and idle
+/// threads steal from busy ones, so threads adding lots of short tasks
+/// aren't all fighting over a single lock. Work runs in no particular order.
+/// Dropping the executor waits for the work already added to run.
+pub struct WorkStealingExecutor {
+    state: Arc<StealingState>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl WorkStealingExecutor {
+    pub fn new(num_threads: usize) -> WorkStealingExecutor {
+        assert!(num_threads > 0, "WorkStealingExecutor needs at least one thread");
+        let state = Arc::new(StealingState {
+            queues: (0..num_threads).map(|_| Mutex::new(VecDeque::new())).collect(),
+            next: AtomicUsize::new(0),
+            pending: AtomicUsize::new(0),
+            sleepers: AtomicUsize::new(0),
+            sleep_lock: Mutex::new(false),
+            wakeup: Condvar::new(),
+        });
+        let threads = (0..num_threads)
+            .map(|index| {
+                let state = state.clone();
+                thread::spawn(move || WorkStealingExecutor::run(&state, index))
+            })
+            .collect();
+        WorkStealingExecutor {
+            state: state,
+            threads: threads,
+        }
+    }
+
+    fn run(state: &StealingState, index: usize) {
+        loop {
+            if let Some(work) = state.find_work(index) {
+                state.pending.fetch_sub(1, Ordering::AcqRel);
+                run_task(work);
+                continue;
+            }
+            let shutdown = state.sleep_lock.lock().unwrap();
+            // Announce we're going to sleep before the last look at pending,
+            // and add bumps pending before looking at sleepers, so at least
+            // one of us sees the other. SeqCst keeps the store and the load
+            // on each side in order.
+            state.sleepers.fetch_add(1, Ordering::SeqCst);
+            // pending counts work that is queued or about to be, so if it's
+            // nonzero go looking again rather than sleep
+            if state.pending.load(Ordering::SeqCst) > 0 || *shutdown {
+                state.sleepers.fetch_sub(1, Ordering::SeqCst);
+                if *shutdown && state.pending.load(Ordering::SeqCst) == 0 {
+                    return;
+                }
+                continue;
+            }
+            let _guard = state.wakeup.wait(shutdown).unwrap();
+            state.sleepers.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
+
+impl Drop for WorkStealingExecutor {
+    fn drop(&mut self) {
+        *self.state.sleep_lock.lock().unwrap() = true;
+        self.state.wakeup.notify_all();
+        for thread in self.threads.drain(..) {
+            if !is_current_thread(&thread) {
+                let _res = thread.join();
+            }
+        }
+    }
+}
+
+impl Executor for WorkStealingExecutor {
+    fn add(&self, work: Box<FnBox() + Send>) -> () {
+        let state = &self.state;
+        state.pending.fetch_add(1, Ordering::SeqCst);
+        let index = state.next.fetch_add(1, Ordering::Relaxed) % state.queues.len();
+        state.queues[index].lock().unwrap().push_back(with_context(work));
+        if state.sleepers.load(Ordering::SeqCst) > 0 {
+            let _lock = state.sleep_lock.lock().unwrap();
+            state.wakeup.notify_one();
+        }
+    }
+
+    fn num_pending(&self) -> usize {
+        return self.state.pending.load(Ordering::Acquire);
+    }
+}
+
+#[test]
+fn test_thread_pool_executor() {
+    use std::sync::mpsc::channel;
+
+    let pool = ThreadPoolExecutor::new(4);
+    let (tx, rx) = channel();
+    for i in 0..100 {
+        let tx = tx.clone();
+        pool.add(Box::new(move || {
+            tx.send(i).unwrap();
+        }));
+    }
+    let mut ran: Vec<usize> = rx.iter().take(100).collect();
+    ran.sort();
+    assert_eq!(ran, (0..100).collect::<Vec<_>>());
+}
+
+#[test]
+fn test_thread_pool_executor_drop_on_pool_thread() {
+    use std::sync::mpsc::channel;
+
+    let pool = Arc::new(ThreadPoolExecutor::new(2));
+    let (go_tx, go_rx) = channel();
+    let (done_tx, done_rx) = channel();
+    let last_ref = pool.clone();
+    pool.add(Box::new(move || {
+        go_rx.recv().unwrap();
+        drop(last_ref);
+        done_tx.send(()).unwrap();
+    }));
+    drop(pool);
+    go_tx.send(()).unwrap();
+    done_rx.recv().unwrap();
+}
+
+#[test]
+fn test_add_preserves_context() {
+    use std::sync::mpsc::channel;
+
+    let pool = ThreadPoolExecutor::new(2);
+    let ctxt = RequestContext::create();
+    ctxt.set_context_data("trace_id", 1234 as usize);
+    let (tx, rx) = channel();
+    pool.add(Box::new(move || {
+        let ctxt = RequestContext::save_context();
+        tx.send(ctxt.get_context_data::<usize>("trace_id")).unwrap();
+    }));
+    assert_eq!(rx.recv().unwrap(), Some(1234));
+    RequestContext::clear_context();
+}
+
+#[test]
+fn test_work_stealing_executor_runs_once() {
+    use microspinlock::spawn_unsafe;
+
+    let counts: Arc<Vec<AtomicUsize>> =
+        Arc::new((0..4000).map(|_| AtomicUsize::new(0)).collect());
+    {
+        let pool = WorkStealingExecutor::new(4);
+        let children: Vec<_> = (0..4)
+            .map(|t| {
+                let pool = &pool;
+                let counts = &counts;
+                unsafe {
+                    spawn_unsafe(move || {
+                        for i in 0..1000 {
+                            let counts = counts.clone();
+                            pool.add(Box::new(move || {
+                                counts[t * 1000 + i].fetch_add(1, Ordering::AcqRel);
+                            }));
+                        }
+                    })
+                }
+            })
+            .collect();
+        for child in children {
+            child.join().unwrap();
+        }
+        // Dropping the pool waits for the work to finish
+    }
+    assert!(counts.iter().all(|count| count.load(Ordering::Acquire) == 1));
+}
+
+#[test]
+fn test_work_stealing_executor_panic() {
+    use std::sync::mpsc::channel;
+
+    let pool = WorkStealingExecutor::new(1);
+    let (tx, rx) = channel();
+    pool.add(Box::new(|| panic!("task failed")));
+    pool.add(Box::new(move || {
+        tx.send(1).unwrap();
+    }));
+    // The worker survives the panic
+    assert_eq!(rx.recv().unwrap(), 1);
+}
+
+#[test]
+fn test_manual_executor() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static CNTR: AtomicUsize = AtomicUsize::new(0);
+    let manual = Arc::new(ManualExecutor::new());
+    for _ in 0..5 {
+        let inner = manual.clone();
+        manual.add(Box::new(move || {
+            CNTR.fetch_add(1, Ordering::AcqRel);
+            // Work added by work waits for the next run
+            inner.add(Box::new(|| {
+                CNTR.fetch_add(10, Ordering::AcqRel);
+            }));
+        }));
+    }
+    assert_eq!(manual.num_pending(), 5);
+    assert_eq!(CNTR.load(Ordering::Acquire), 0);
+    assert_eq!(manual.run(), 5);
+    assert_eq!(CNTR.load(Ordering::Acquire), 5);
+    assert_eq!(manual.num_pending(), 5);
+    assert_eq!(manual.run(), 5);
+    assert_eq!(CNTR.load(Ordering::Acquire), 55);
+    assert_eq!(manual.num_pending(), 0);
+}
+
+#[test]
+fn test_try_add() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Rejects work once `capacity` tasks are pending
+    struct Capped {
+        manual: ManualExecutor,
+        capacity: usize,
+    }
+
+    impl Executor for Capped {
+        fn add(&self, work: Box<FnBox() + Send>) -> () {
+            self.manual.add(work);
+        }
+
+        fn try_add(&self, work: Box<FnBox() + Send>) -> Result<(), Box<FnBox() + Send>> {
+            if self.manual.num_pending() >= self.capacity {
+                return Err(work);
+            }
+            self.add(work);
+            return Ok(());
+        }
+    }
+
+    static CNTR: AtomicUsize = AtomicUsize::new(0);
+    let inline = InlineExecutor::new();
+    assert!(inline.try_add(Box::new(|| {
+            CNTR.fetch_add(1, Ordering::AcqRel);
+        }))
+        .is_ok());
+    assert_eq!(CNTR.load(Ordering::Acquire), 1);
+
+    let capped = Capped {
+        manual: ManualExecutor::new(),
+        capacity: 1,
+    };
+    assert!(capped.try_add(Box::new(|| {
+            CNTR.fetch_add(1, Ordering::AcqRel);
+        }))
+        .is_ok());
+    let rejected = capped.try_add(Box::new(|| {
+        CNTR.fetch_add(10, Ordering::AcqRel);
+    }));
+    // The rejected work is handed back and can still be run
+    match rejected {
+        Ok(()) => panic!("expected the work to be rejected"),
+        Err(work) => work.call_box(()),
+    }
+    assert_eq!(CNTR.load(Ordering::Acquire), 11);
+    assert_eq!(capped.manual.run(), 1);
+    assert_eq!(CNTR.load(Ordering::Acquire), 12);
+}
+
+#[test]
+fn test_num_pending() {
+    let inline = InlineExecutor::new();
+    inline.add(Box::new(|| {}));
+    assert_eq!(inline.num_pending(), 0);
+
+    let queued = QueuedImmediateExecutor::new();
+    queued.add(Box::new(|| {
+        let queued = QueuedImmediateExecutor::new();
+        queued.add(Box::new(|| {}));
+        queued.add(Box::new(|| {}));
+        assert_eq!(queued.num_pending(), 2);
+    }));
+    assert_eq!(queued.num_pending(), 0);
+
+    let shared = QueuedExecutor::new();
+    let inner = shared.clone();
+    shared.add(Box::new(move || {
+        inner.add(Box::new(|| {}));
+        assert_eq!(inner.num_pending(), 1);
+    }));
+    assert_eq!(shared.num_pending(), 0);
+}
+
+#[test]
+fn test_queued_executor_shared() {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+
+    let queued = QueuedExecutor::new();
+    let cntr = Arc::new(AtomicUsize::new(0));
+    let children: Vec<_> = (0..2)
+        .map(|_| {
+            let queued = queued.clone();
+            let cntr = cntr.clone();
+            thread::spawn(move || {
+                for _ in 0..1000 {
+                    let cntr = cntr.clone();
+                    queued.add(Box::new(move || {
+                        cntr.fetch_add(1, Ordering::AcqRel);
+                    }));
+                }
+            })
+        })
+        .collect();
+    for child in children {
+        child.join().unwrap();
+    }
+    assert_eq!(cntr.load(Ordering::Acquire), 2000);
+}
+
+#[test]
+fn test_queued_executor_shared_nested() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static CNTR: AtomicUsize = AtomicUsize::new(0);
+    let queued = QueuedExecutor::new();
+    let inner = queued.clone();
+    queued.add(Box::new(move || {
+        inner.add(Box::new(|| {
+            // Should happen last
+            assert_eq!(CNTR.load(Ordering::Acquire), 1);
+            CNTR.fetch_add(1, Ordering::AcqRel);
+        }));
+        assert_eq!(CNTR.load(Ordering::Acquire), 0);
+        CNTR.fetch_add(1, Ordering::AcqRel);
+    }));
+    assert_eq!(CNTR.load(Ordering::Acquire), 2);
+}
+
+#[test]
+fn test_add_batch() {
+    use std::sync::Mutex;
+
+    let order = Arc::new(Mutex::new(Vec::new()));
+    let batch = |executor: &Executor| {
+        let work: Vec<Box<FnBox() + Send>> = (0..3)
+            .map(|i| {
+                let order = order.clone();
+                Box::new(move || order.lock().unwrap().push(i)) as Box<FnBox() + Send>
+            })
+            .collect();
+        executor.add_batch(work);
+    };
+
+    // The default goes through add
+    batch(&InlineExecutor::new());
+    assert_eq!(*order.lock().unwrap(), vec![0, 1, 2]);
+    order.lock().unwrap().clear();
+
+    batch(&QueuedExecutor::new());
+    assert_eq!(*order.lock().unwrap(), vec![0, 1, 2]);
+    order.lock().unwrap().clear();
+
+    let manual = ManualExecutor::new();
+    batch(&manual);
+    assert_eq!(manual.num_pending(), 3);
+    assert!(order.lock().unwrap().is_empty());
+    assert_eq!(manual.run(), 3);
+    assert_eq!(*order.lock().unwrap(), vec![0, 1, 2]);
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::boxed::FnBox;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use test::Bencher;
+
+    use alloc_count::count_allocations;
+    use microspinlock::spawn_unsafe;
+    use super::{Executor, ManualExecutor, QueuedImmediateExecutor, ThreadPoolExecutor,
+                WorkStealingExecutor};
+
+    /// Draining tracks itself with the DRAINING flag rather than a
+    /// placeholder task, so each add allocates only the RequestContext
+    /// wrapper around its work.
+    #[bench]
+    fn bench_queued_executor_nested(b: &mut Bencher) {
+        let queued = QueuedImmediateExecutor::new();
+        let nested = || {
+            queued.add(Box::new(|| {
+                let queued = QueuedImmediateExecutor::new();
+                queued.add(Box::new(|| {}));
+                queued.add(Box::new(|| {}));
+            }));
+        };
+        // The first run sizes the thread's queue
+        nested();
+        assert_eq!(count_allocations(&nested).0, 3);
+        b.iter(&nested);
+    }
+
+    #[bench]
+    fn bench_manual_executor_add_64(b: &mut Bencher) {
+        let manual = ManualExecutor::new();
+        b.iter(|| {
+            for _ in 0..64 {
+                manual.add(Box::new(|| {}));
+            }
+            manual.run();
+        });
+    }
+
+    #[bench]
+    fn bench_manual_executor_add_batch_64(b: &mut Bencher) {
+        let manual = ManualExecutor::new();
+        b.iter(|| {
+            let work: Vec<Box<FnBox() + Send>> =
+                (0..64).map(|_| Box::new(|| {}) as Box<FnBox() + Send>).collect();
+            manual.add_batch(work);
+            manual.run();
+        });
+    }
+
+    /// Four threads each add 1000 tiny tasks, then wait for all of them to
+    /// have run.
+    fn bench_fan_out(b: &mut Bencher, pool: &Executor) {
+        let done = Arc::new(AtomicUsize::new(0));
+        b.iter(|| {
+            done.store(0, Ordering::Release);
+            let children: Vec<_> = (0..4)
+                .map(|_| unsafe {
+                    spawn_unsafe(|| {
+                        for _ in 0..1000 {
+                            let done = done.clone();
+                            pool.add(Box::new(move || {
+                                done.fetch_add(1, Ordering::AcqRel);
+                            }));
+                        }
+                    })
+                })
+                .collect();
+            for child in children {
+                let _res = child.join();
+            }
+            while done.load(Ordering::Acquire) < 4000 {
+                thread::yield_now();
+            }
+        });
+    }
+
+    #[bench]
+    fn bench_thread_pool_executor_fan_out(b: &mut Bencher) {
+        bench_fan_out(b, &ThreadPoolExecutor::new(4));
+    }
+
+    #[bench]
+    fn bench_work_stealing_executor_fan_out(b: &mut Bencher) {
+        bench_fan_out(b, &WorkStealingExecutor::new(4));
+    }
+}
@@ -1,55 +1,154 @@
+use std::any::Any;
+use std::cmp;
+use std::collections::VecDeque;
+use std::fmt;
 use std::io::{Error, ErrorKind};
+use std::mem;
 use std::ptr;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use std::vec;
 
-use detail::core::Core;
-use executor::{Executor};
-use promise::Promise;
-use try::Try;
+use detail::core::{Core, State};
+use error::{ChainError, ErrorOrigin, FutureError, clone_error};
+use executor::{Executor, QueuedImmediateExecutor, TimerExecutor};
+use microspinlock::SpinMutex;
+use promise::{Promise, SharedPromise};
+use try::{Try, TryUsageError};
 
 
-pub struct Future<T> {
-    core_ptr: *mut Core<T>,
+/// Where a future is in its lifecycle, see Future::state.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FutureState {
+    /// The future has been detached from its core
+    NoState,
+    /// Neither a result nor a callback has been set
+    Start,
+    /// The result is set and is waiting for a callback
+    OnlyResult,
+    /// A callback is set and is waiting for the result
+    OnlyCallback,
+    /// Both are set and the callback is about to run
+    Armed,
+    /// The callback has been run, or handed to the executor
+    Done,
 }
 
-impl<T> Drop for Future<T> {
+/// The consumer side of a Core. A Future is the only owner of its
+/// reference to the Core: it is neither Copy nor Clone, methods that consume
+/// it give the reference up exactly once when it's dropped, and once
+/// detached its core pointer is null, so the reference is never given up
+/// twice.
+///
+/// As with Promise the error type `E` defaults to io::Error. The basic
+/// operations work with any `E` that an io::Error, and so the library's own
+/// errors, converts into; the other combinators are for io::Errors only.
+pub struct Future<T, E = Error> {
+    core_ptr: *mut Core<T, E>,
+}
+
+impl<T, E> Drop for Future<T, E> {
     fn drop(&mut self) {
         self.detach();
     }
 }
 
-impl<T> Future<T> {
-    pub fn new_core_ptr(core_ptr: *mut Core<T>) -> Future<T> {
+/// A Future can be moved to another thread, its Core synchronizes it with
+/// the Promise side. Callbacks may be handed the value on yet another.
+unsafe impl<T: Send, E: Send> Send for Future<T, E> {}
+
+impl<T, E> fmt::Debug for Future<T, E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.core_ptr.is_null() {
+            return write!(f, "Future {{ core: null }}");
+        }
+        let (state, has_result) = unsafe { (*self.core_ptr).debug_state() };
+        f.debug_struct("Future")
+            .field("state", &state)
+            .field("has_result", &has_result)
+            .finish()
+    }
+}
+
+impl<T, E> Future<T, E> {
+    /// Take ownership of the Future side of `core_ptr`'s reference count.
+    /// Unsafe because there must be exactly one Future per Core, otherwise
+    /// each of them would detach the same reference when dropped.
+    pub unsafe fn new_core_ptr(core_ptr: *mut Core<T, E>) -> Future<T, E> {
         Future { core_ptr: core_ptr }
     }
 
-    pub fn new(try: Try<T>) -> Future<T> {
+    /// Like new, for any error type.
+    pub fn from_try(try: Try<T, E>) -> Future<T, E> {
         Future { core_ptr: Box::into_raw(Box::new(Core::new_try(try))) }
     }
 
     fn detach(&mut self) {
         unsafe {
-            (*self.core_ptr).detach_future();
-            self.core_ptr = ptr::null_mut();
+            if !self.core_ptr.is_null() {
+                (*self.core_ptr).detach_future();
+                self.core_ptr = ptr::null_mut();
+            }
         }
     }
 
-    pub fn get_executor(&self) -> *const Executor {
+    /// The executor callbacks run on, or None if they run inline.
+    pub fn get_executor(&self) -> Option<Arc<Executor + Send + Sync>> {
+        if self.core_ptr.is_null() {
+            return None;
+        }
         unsafe { (*self.core_ptr).get_executor() }
     }
 
-    pub fn set_executor(&self, x: *const Executor) {
+    /// Run callbacks on `x` rather than inline. The future holds a
+    /// reference, so `x` lives at least as long as it's needed.
+    pub fn set_executor(&self, x: Arc<Executor + Send + Sync>)
+        where T: Send + 'static,
+              E: Send + 'static
+    {
+        if self.core_ptr.is_null() {
+            return;
+        }
         unsafe { (*self.core_ptr).set_executor(x, -1) }
     }
 
+    /// A snapshot of where this future is in its lifecycle, which may
+    /// change as soon as it's returned.
+    pub fn state(&self) -> FutureState {
+        if self.core_ptr.is_null() {
+            return FutureState::NoState;
+        }
+        match unsafe { (*self.core_ptr).get_state() } {
+            State::Start => FutureState::Start,
+            State::OnlyResult => FutureState::OnlyResult,
+            State::OnlyCallback => FutureState::OnlyCallback,
+            State::Armed => FutureState::Armed,
+            State::Done => FutureState::Done,
+        }
+    }
+
+    /// Request that the work backing this future stop, e.g. because the
+    /// result is no longer needed. The promise's interrupt handler, if any,
+    /// sees `err`. Does nothing once the result is already set.
+    pub fn raise(&self, err: Error) {
+        if self.core_ptr.is_null() {
+            return;
+        }
+        unsafe {
+            (*self.core_ptr).raise(err);
+        }
+    }
+
     fn error_if_invalid(&self) -> Result<(), Error> {
         if self.core_ptr.is_null() {
-            return Err(Error::new(ErrorKind::Other, "No state"));
+            return Err(FutureError::NoState.into());
         }
         return Ok(());
     }
 
     fn set_callback<F>(&mut self, func: F) -> Result<(), Error>
-        where F: FnOnce(Try<T>) + 'static
+        where F: FnOnce(Try<T, E>) + Send + 'static
     {
         try!(self.error_if_invalid());
         unsafe {
@@ -57,112 +156,2756 @@ impl<T> Future<T> {
         }
     }
 
-    pub fn then<F, U>(&mut self, func: F) -> Result<Future<U>, Error>
-        where F: FnOnce(Try<T>) -> Future<U> + 'static,
-              U: 'static
+    /// Make the promise for a combinator's downstream future, which inherits
+    /// this future's interrupt handler and executor.
+    fn downstream<U>(&self) -> Result<(Promise<U, E>, Future<U, E>), Error>
+        where U: Send + 'static,
+              E: From<Error> + Send + 'static
     {
         try!(self.error_if_invalid());
-        let mut p: Promise<U> = Promise::new();
+        let mut p: Promise<U, E> = Promise::default();
         unsafe {
             if let Some(handler) = (*self.core_ptr).get_interrupt_handler() {
                 (*p.core_ptr).set_interrupt_handler_nolock(handler);
             }
         }
         let f = try!(p.get_future());
-        f.set_executor(self.get_executor());
+        if let Some(executor) = self.get_executor() {
+            f.set_executor(executor);
+        }
+        return Ok((p, f));
+    }
 
+    pub fn then<F, U>(&mut self, func: F) -> Result<Future<U, E>, Error>
+        where F: FnOnce(Try<T, E>) -> Future<U, E> + Send + 'static,
+              U: Send + 'static,
+              E: From<Error> + From<TryUsageError> + Send + 'static
+    {
+        let (p, f) = try!(self.downstream());
         self.set_callback(move |try| {
+            chain(try, func, p);
+        });
+        return Ok(f);
+    }
+
+    /// Like then, for a `func` returning a plain value. As with then,
+    /// `func` is this future's callback, so Core runs it on this future's
+    /// executor if it has one, and only runs it inline when it doesn't.
+    pub fn then_val<F, U>(&mut self, func: F) -> Result<Future<U, E>, Error>
+        where F: FnOnce(Try<T, E>) -> U + Send + 'static,
+              U: Send + 'static,
+              E: From<Error> + From<TryUsageError> + Send + 'static
+    {
+        return self.then_try(move |try| {
             if try.has_error() {
-                p.set_error(try);
+                Try::new_error(try.get_error())
             } else {
-                let mut f2 = func(try);
-                f2.set_callback(move |try2| {
-                    p.set_try(try2);
-                });
+                Try::new_value(func(try))
             }
         });
+    }
+
+    /// Unlike then and then_val the callback also sees errors, and the Try
+    /// it returns is stored directly in the downstream future, so it can
+    /// fail or recover without building a Future. If the callback panics
+    /// the downstream future fails with the panic message.
+    pub fn then_try<F, U>(&mut self, func: F) -> Result<Future<U, E>, Error>
+        where F: FnOnce(Try<T, E>) -> Try<U, E> + Send + 'static,
+              U: Send + 'static,
+              E: From<Error> + Send + 'static
+    {
+        let (p, f) = try!(self.downstream());
+        try!(self.set_callback(move |try| {
+            let res = Try::with_panic(move || func(try));
+            p.set_try(match res.value() {
+                Ok(try) => try,
+                Err(err) => Try::new_error(E::from(err)),
+            });
+        }));
         return Ok(f);
     }
 
-    pub fn then_val<F, U>(&mut self, func: F) -> Result<Future<U>, Error>
-        where F: FnOnce(Try<T>) -> U + 'static,
-              U: 'static
+    /// Let `func` observe the result, e.g. for logging or metrics, then
+    /// pass it through unchanged.
+    pub fn tap<F>(&mut self, func: F) -> Result<Future<T, E>, Error>
+        where F: FnOnce(&Try<T, E>) + Send + 'static,
+              T: Send + 'static,
+              E: From<Error> + Send + 'static
+    {
+        return self.then_try(move |try| {
+            func(&try);
+            return try;
+        });
+    }
+
+    /// Have `func` told when the result is set, e.g. to wake an event loop,
+    /// without consuming it, so value() still works afterwards. `func` runs
+    /// on whichever thread sets the result, or right away if it's set.
+    pub fn on_ready<F>(&mut self, func: F)
+        where F: FnOnce() + Send + 'static
+    {
+        if self.core_ptr.is_null() {
+            return;
+        }
+        unsafe {
+            (*self.core_ptr).set_ready_callback(Box::new(func));
+        }
+    }
+
+    /// Block until the result is set and return the value, panicking with
+    /// the error's message if it failed, like Result::unwrap.
+    pub fn get(self) -> T
+        where T: Send + 'static,
+              E: From<Error> + From<TryUsageError> + fmt::Display + Send + 'static
+    {
+        match self.wait() {
+            Ok(val) => val,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    /// Block until the result is set and return the error, panicking if it
+    /// succeeded instead, like Result::unwrap_err.
+    pub fn unwrap_err(self) -> E
+        where T: Send + 'static,
+              E: From<Error> + From<TryUsageError> + Send + 'static
+    {
+        match self.wait() {
+            Ok(_) => panic!("called unwrap_err on a future that succeeded"),
+            Err(err) => err,
+        }
+    }
+
+    /// Borrow the result, if it is ready, without consuming it. Returns None
+    /// once a callback has taken the result. Taking the result needs `&mut
+    /// self`, so it can't happen while the borrow is held.
+    pub fn peek(&self) -> Option<&Try<T, E>> {
+        if self.core_ptr.is_null() {
+            return None;
+        }
+        unsafe {
+            return (*self.core_ptr).peek_try();
+        }
+    }
+
+    /// Block until the result is set, then return it.
+    pub fn wait(mut self) -> Result<T, E>
+        where T: Send + 'static,
+              E: From<Error> + From<TryUsageError> + Send + 'static
+    {
+        let slot = Arc::new((Mutex::new(None), Condvar::new()));
+        let callback_slot = slot.clone();
+        try!(self.set_callback(move |try| {
+            let &(ref lock, ref cvar) = &*callback_slot;
+            *lock.lock().unwrap() = Some(try);
+            cvar.notify_one();
+        }));
+        let &(ref lock, ref cvar) = &*slot;
+        let mut result = lock.lock().unwrap();
+        while result.is_none() {
+            result = cvar.wait(result).unwrap();
+        }
+        return result.take().unwrap().value();
+    }
+
+    pub fn value(&mut self) -> Result<T, E>
+        where E: From<Error> + From<TryUsageError>
     {
         try!(self.error_if_invalid());
-        let mut p: Promise<U> = Promise::new();
         unsafe {
-            if let Some(handler) = (*self.core_ptr).get_interrupt_handler() {
-                (*p.core_ptr).set_interrupt_handler_nolock(handler);
+            return try!((*self.core_ptr).get_try()).value();
+        }
+    }
+
+    /// Returns the value if this future has already succeeded, otherwise
+    /// the result of `func`, without blocking. A future that failed or that
+    /// a callback has already consumed also gets `func`.
+    pub fn ready_or_else<F>(mut self, func: F) -> T
+        where F: FnOnce() -> T,
+              E: From<Error> + From<TryUsageError>
+    {
+        if self.peek().is_some() {
+            if let Ok(val) = self.value() {
+                return val;
             }
         }
+        return func();
+    }
+}
 
-        let f = try!(p.get_future());
-        f.set_executor(self.get_executor());
-        self.set_callback(move |try| {
+impl<T> Future<T> {
+    pub fn new(try: Try<T>) -> Future<T> {
+        return Future::from_try(try);
+    }
+
+    /// Like new, but callbacks run on `exec` from the very first one, as
+    /// there is no window before set_executor where one could run inline.
+    pub fn new_on(try: Try<T>, exec: Arc<Executor + Send + Sync>) -> Future<T>
+        where T: Send + 'static
+    {
+        let mut core = Core::new_try(try);
+        core.set_executor(exec, -1);
+        Future { core_ptr: Box::into_raw(Box::new(core)) }
+    }
+
+    /// Run `func` on `exec`, settling the returned future with its result.
+    pub fn defer<F>(exec: Arc<Executor + Send + Sync>, func: F) -> Future<T>
+        where F: FnOnce() -> Try<T> + Send + 'static,
+              T: Send + 'static
+    {
+        let mut p: Promise<T> = Promise::new();
+        let f = match p.get_future() {
+            Ok(f) => f,
+            Err(err) => return Future::new(Try::new_error(err)),
+        };
+        exec.add(Box::new(move || {
+            let _res = p.set_try(func());
+        }));
+        return f;
+    }
+
+    /// Like defer, for a `func` returning a plain value. If `func` panics
+    /// the future fails with the panic message.
+    pub fn defer_value<F>(exec: Arc<Executor + Send + Sync>, func: F) -> Future<T>
+        where F: FnOnce() -> T + Send + 'static,
+              T: Send + 'static
+    {
+        return Future::defer(exec, move || Try::with_panic(func));
+    }
+
+    /// Like then, but `func` runs on `exec` whatever this future's executor
+    /// is, and the downstream future uses `exec` too.
+    pub fn then_via<F, U>(&mut self,
+                          exec: Arc<Executor + Send + Sync>,
+                          func: F)
+                          -> Result<Future<U>, Error>
+        where F: FnOnce(Try<T>) -> Future<U> + Send + 'static,
+              T: Send + 'static,
+              U: Send + 'static
+    {
+        let (p, f) = try!(self.downstream());
+        f.set_executor(exec.clone());
+        try!(self.set_callback(move |try| {
+            exec.add(Box::new(move || chain(try, func, p)));
+        }));
+        return Ok(f);
+    }
+
+    /// Like then, but the downstream error says where it came from: errors
+    /// from this future are tagged ErrorOrigin::Source, and errors from
+    /// `func` or the future it returns ErrorOrigin::Continuation. The tag
+    /// keeps the error's kind and prefixes its message, see ChainError.
+    pub fn then_traced<F, U>(&mut self, func: F) -> Result<Future<U>, Error>
+        where F: FnOnce(Try<T>) -> Future<U> + Send + 'static,
+              U: Send + 'static
+    {
+        let (p, f) = try!(self.downstream());
+        try!(self.set_callback(move |try| {
             if try.has_error() {
-                p.set_error(try);
-            } else {
-                // TODO(ptc) see if this is right to just call this in-line
-                p.set_try(Try::new_value(func(try)));
+                let err = ChainError::wrap(ErrorOrigin::Source, try.get_error());
+                let _res = p.set_error_direct(err);
+                return;
+            }
+            let next = match Try::with_panic(move || func(try)).value() {
+                Ok(next) => next,
+                Err(err) => {
+                    let _res = p.set_error_direct(ChainError::wrap(ErrorOrigin::Continuation, err));
+                    return;
+                }
+            };
+            let mut next = next;
+            // If next can't take a callback p is dropped, breaking the promise
+            let _res = next.set_callback(move |try2| {
+                let try2 = try2.map_error(|err| ChainError::wrap(ErrorOrigin::Continuation, err));
+                let _res = p.set_try(try2);
+            });
+        }));
+        return Ok(f);
+    }
+
+    /// Like then_try, but `func` returns a Result, so it can map a value or
+    /// an error to either outcome with the usual Result combinators.
+    pub fn transform<F, U>(&mut self, func: F) -> Result<Future<U>, Error>
+        where F: FnOnce(Try<T>) -> Result<U, Error> + Send + 'static,
+              U: Send + 'static
+    {
+        return self.then_try(move |try| Try::from(func(try)));
+    }
+
+    /// Like tap, but `func` only sees the value, and isn't called if this
+    /// future fails.
+    pub fn inspect<F>(&mut self, func: F) -> Result<Future<T>, Error>
+        where F: FnOnce(&T) + Send + 'static,
+              T: Send + 'static
+    {
+        return self.tap(move |try| {
+            if let Some(val) = try.ok() {
+                func(val);
+            }
+        });
+    }
+
+    /// The error side of inspect: `func` sees the error, e.g. to log it,
+    /// and isn't called if this future succeeds. The error still reaches
+    /// the downstream future.
+    pub fn inspect_error<F>(&mut self, func: F) -> Result<Future<T>, Error>
+        where F: FnOnce(&Error) + Send + 'static,
+              T: Send + 'static
+    {
+        return self.tap(move |try| {
+            if let Some(err) = try.err() {
+                func(err);
+            }
+        });
+    }
+
+    /// Drop the value once this future succeeds, for when only completion
+    /// matters. Errors pass through.
+    pub fn unit(&mut self) -> Result<Future<()>, Error>
+        where T: Send + 'static
+    {
+        return self.then_try(|try| try.map(|_| ()));
+    }
+
+    /// Rewrite the error if this future fails, e.g. to add context or change
+    /// its kind. Values pass through untouched.
+    pub fn map_error<F>(&mut self, func: F) -> Result<Future<T>, Error>
+        where F: FnOnce(Error) -> Error + Send + 'static,
+              T: Send + 'static
+    {
+        return self.then_try(move |try| try.map_error(func));
+    }
+
+    /// Recover from an error of `kind` with the value `func` makes from it.
+    /// Other errors, and values, pass through untouched.
+    pub fn on_error_kind<F>(&mut self, kind: ErrorKind, func: F) -> Result<Future<T>, Error>
+        where F: FnOnce(Error) -> T + Send + 'static,
+              T: Send + 'static
+    {
+        return self.then_try(move |try| {
+            let matches = try.err().map_or(false, |err| err.kind() == kind);
+            if matches {
+                return Try::new_value(func(try.get_error()));
             }
+            return try;
         });
+    }
+
+    /// Use this future's value if it succeeds, otherwise the result of
+    /// `backup`. The backup is only waited on if this future fails, and is
+    /// detached otherwise.
+    pub fn fallback_to(&mut self, backup: Future<T>) -> Result<Future<T>, Error>
+        where T: Send + 'static
+    {
+        let (p, f) = try!(self.downstream());
+        try!(self.set_callback(move |try| {
+            if try.has_value() {
+                let _res = p.set_try(try);
+                return;
+            }
+            // If backup can't take a callback, this error is passed on rather
+            // than breaking the promise
+            let promise = Arc::new(SpinMutex::new(Some(p)));
+            let backup_promise = promise.clone();
+            let mut backup = backup;
+            let res = backup.set_callback(move |try2| {
+                if let Some(p) = backup_promise.lock().take() {
+                    let _res = p.set_try(try2);
+                }
+            });
+            if res.is_err() {
+                if let Some(p) = promise.lock().take() {
+                    let _res = p.set_try(try);
+                }
+            }
+        }));
         return Ok(f);
     }
 
-    pub fn value(&self) -> Result<T, Error> {
+    /// Once this future succeeds, settle with `next`'s result, dropping this
+    /// one's value. If this future fails its error is passed on and `next`
+    /// is detached without being waited on.
+    pub fn and<U>(&mut self, next: Future<U>) -> Result<Future<U>, Error>
+        where U: Send + 'static
+    {
+        let (p, f) = try!(self.downstream());
+        try!(self.set_callback(move |try| {
+            if try.has_error() {
+                let _res = p.set_error(try);
+                return;
+            }
+            // If next can't take a callback, the error saying so is passed on
+            // rather than breaking the promise
+            let promise = Arc::new(SpinMutex::new(Some(p)));
+            let next_promise = promise.clone();
+            let mut next = next;
+            let res = next.set_callback(move |try2| {
+                if let Some(p) = next_promise.lock().take() {
+                    let _res = p.set_try(try2);
+                }
+            });
+            if let Err(err) = res {
+                if let Some(p) = promise.lock().take() {
+                    let _res = p.set_error_direct(err);
+                }
+            }
+        }));
+        return Ok(f);
+    }
+
+    /// Fails with a "Value predicate failed" error if the value doesn't
+    /// satisfy `pred`, otherwise passes it through. Errors pass through.
+    pub fn filter<F>(&mut self, pred: F) -> Result<Future<T>, Error>
+        where F: FnOnce(&T) -> bool + Send + 'static,
+              T: Send + 'static
+    {
+        return self.then_try(move |try| {
+            if !try.has_value() || pred(try.ok().unwrap()) {
+                return try;
+            }
+            return Try::new_error(Error::new(ErrorKind::Other, "Value predicate failed"));
+        });
+    }
+
+    /// Fold the values of `futures`, in order, into `init`. Each value is
+    /// folded in as soon as it and everything before it have settled. The
+    /// result is the first error encountered, if any.
+    pub fn reduce<U, F>(futures: Vec<Future<T>>, init: U, func: F) -> Future<U>
+        where F: Fn(U, T) -> U + Send + Sync + 'static,
+              T: Send + 'static,
+              U: Send + 'static
+    {
+        let func = Arc::new(func);
+        let mut acc = Future::new(Try::new_value(init));
+        for mut future in futures {
+            let func = func.clone();
+            let next = acc.then(move |acc_try| {
+                let acc_val = acc_try.value().unwrap();
+                match future.then_val(move |try| func(acc_val, try.value().unwrap())) {
+                    Ok(f) => f,
+                    Err(err) => Future::new(Try::new_error(err)),
+                }
+            });
+            acc = match next {
+                Ok(f) => f,
+                Err(err) => Future::new(Try::new_error(err)),
+            };
+        }
+        return acc;
+    }
+
+    /// Call `factory` for a future, and if it fails call it again, up to
+    /// `retries` more times. Each attempt starts only once the previous one
+    /// has settled. The result is that of the last attempt.
+    pub fn retry<F>(factory: F, retries: usize) -> Future<T>
+        where F: Fn() -> Future<T> + Send + Sync + 'static,
+              T: Send + 'static
+    {
+        let mut p: Promise<T> = Promise::new();
+        let f = match p.get_future() {
+            Ok(f) => f,
+            Err(err) => return Future::new(Try::new_error(err)),
+        };
+        retry_attempt(Arc::new(factory), retries, p);
+        return f;
+    }
+
+    /// Fails with a TimedOut error unless this future settles within `dur`.
+    /// The deadline is tracked by the global TimerExecutor.
+    pub fn within(&mut self, dur: Duration) -> Result<Future<T>, Error>
+        where T: Send + 'static
+    {
+        return self.within_on(TimerExecutor::global(), dur);
+    }
+
+    /// Like within, but tracks the deadline on `timer`.
+    pub fn within_on(&mut self, timer: &TimerExecutor, dur: Duration) -> Result<Future<T>, Error>
+        where T: Send + 'static
+    {
+        return self.race_timer(timer,
+                               dur,
+                               || Try::new_error(FutureError::TimedOut.into()));
+    }
+
+    /// Settle with this future's result, or with `on_deadline`'s if `dur`
+    /// passes on `timer` first.
+    fn race_timer<F>(&mut self,
+                     timer: &TimerExecutor,
+                     dur: Duration,
+                     on_deadline: F)
+                     -> Result<Future<T>, Error>
+        where F: FnOnce() -> Try<T> + Send + 'static,
+              T: Send + 'static
+    {
+        let (p, f) = try!(self.downstream());
+        // Whichever of the result and the deadline arrives first takes it
+        let promise = Arc::new(SpinMutex::new(Some(p)));
+        let timer_promise = promise.clone();
+        try!(self.set_callback(move |try| {
+            if let Some(p) = promise.lock().take() {
+                let _res = p.set_try(try);
+            }
+        }));
+        timer.schedule(dur,
+                       Box::new(move || {
+                           if let Some(p) = timer_promise.lock().take() {
+                               let _res = p.set_try(on_deadline());
+                           }
+                       }));
+        return Ok(f);
+    }
+
+    /// Like within, but with an absolute deadline, so that stages sharing a
+    /// deadline don't each add their own slack. If the deadline has already
+    /// passed the returned future has already failed.
+    pub fn within_deadline(&mut self, deadline: Instant) -> Result<Future<T>, Error>
+        where T: Send + 'static
+    {
         try!(self.error_if_invalid());
-        unsafe {
-            return try!((*self.core_ptr).get_try()).value();
+        let now = Instant::now();
+        if deadline <= now {
+            return Ok(Future::new(Try::new_error(FutureError::TimedOut.into())));
         }
+        return self.within(deadline - now);
     }
-}
 
+    /// Like within, but settles with the value from `func` instead of
+    /// failing if the deadline passes first. Errors from this future, even
+    /// TimedOut ones, pass through untouched.
+    pub fn on_timeout<F>(&mut self, dur: Duration, func: F) -> Result<Future<T>, Error>
+        where F: FnOnce() -> T + Send + 'static,
+              T: Send + 'static
+    {
+        return self.race_timer(TimerExecutor::global(),
+                               dur,
+                               move || Try::new_value(func()));
+    }
 
-#[cfg(test)]
-mod tests {
+    /// Pairs this future's result with whether it settled within `dur` from
+    /// now, e.g. for SLA metrics. Unlike within it never times out: the
+    /// result is passed along however long it takes. The time is taken when
+    /// the callback runs, so includes any wait for this future's executor.
+    pub fn completed_within(&mut self, dur: Duration) -> Result<Future<(bool, Try<T>)>, Error>
+        where T: Send + 'static
+    {
+        let start = Instant::now();
+        return self.then_try(move |try| Try::new_value((start.elapsed() <= dur, try)));
+    }
 
-    use test::Bencher;
+    /// Passes this future's result through, but no sooner than `dur` from
+    /// now. The delay is tracked by the global TimerExecutor.
+    pub fn delayed(&mut self, dur: Duration) -> Result<Future<T>, Error>
+        where T: Send + 'static
+    {
+        return self.delayed_on(TimerExecutor::global(), dur);
+    }
 
-    use super::Future;
-    use try::Try;
+    /// Like delayed, but tracks the delay on `timer`.
+    pub fn delayed_on(&mut self, timer: &TimerExecutor, dur: Duration) -> Result<Future<T>, Error>
+        where T: Send + 'static
+    {
+        let (p, f) = try!(self.downstream());
+        let state = Arc::new(DelayedState {
+            remaining: AtomicUsize::new(2),
+            result: Mutex::new(None),
+            promise: SpinMutex::new(Some(p)),
+        });
+        let timer_state = state.clone();
+        try!(self.set_callback(move |try| {
+            *state.result.lock().unwrap() = Some(try);
+            state.arrive();
+        }));
+        timer.schedule(dur, Box::new(move || timer_state.arrive()));
+        return Ok(f);
+    }
 
+    /// Settle with whichever of this future and `other` succeeds first. If
+    /// both fail, the error of the one that failed last is passed on.
+    pub fn or(mut self, mut other: Future<T>) -> Future<T>
+        where T: Send + 'static
+    {
+        let mut p: Promise<T> = Promise::new();
+        let f = match p.get_future() {
+            Ok(f) => f,
+            Err(err) => return Future::new(Try::new_error(err)),
+        };
+        let state = Arc::new(OrState {
+            failed: AtomicBool::new(false),
+            promise: SpinMutex::new(Some(p)),
+        });
+        let other_state = state.clone();
+        if let Err(err) = self.set_callback(move |try| or_settled(&state, try)) {
+            return Future::new(Try::new_error(err));
+        }
+        if let Err(err) = other.set_callback(move |try| or_settled(&other_state, try)) {
+            return Future::new(Try::new_error(err));
+        }
+        return f;
+    }
 
-    #[test]
-    fn test_future_value() {
-        let future = Future::new(Try::new_value(0));
-        assert_eq!(future.value().unwrap(), 0);
+    /// Split into two futures that each settle with a copy of this one's
+    /// result, so it can be observed in one place and consumed in another.
+    pub fn split(self) -> (Future<T>, Future<T>)
+        where T: Clone + Send + 'static
+    {
+        let shared = self.share();
+        return (shared.get_future(), shared.get_future());
     }
 
-    #[bench]
-    fn bench_constant_future(b: &mut Bencher) {
-        b.iter(|| {
-            let future = Future::new(Try::new_value(0));
-        })
+    /// Turn into a SharedFuture, which can be cloned and read any number of
+    /// times, each read getting a copy of this future's result.
+    pub fn share(mut self) -> SharedFuture<T>
+        where T: Clone + Send + 'static
+    {
+        let shared = Arc::new(SharedPromise::new());
+        let setter = shared.clone();
+        if let Err(err) = self.set_callback(move |try| {
+            let _res = setter.set_try(try);
+        }) {
+            let _res = shared.set_try(Try::new_error(err));
+        }
+        return SharedFuture { shared: shared };
     }
 
-    #[test]
-    fn test_future_then() {
-        let mut future = Future::new(Try::new_value(0));
-        let res = future.then(|try| {
-                let v = try.value().unwrap();
-                return Future::new(Try::new_value(v + 1));
-            })
-            .unwrap()
-            .value()
-            .unwrap();
-        assert_eq!(res, 1);
+    /// Start a run of synchronous then_val stages that are composed into a
+    /// single callback, so however many there are the run needs only one
+    /// downstream Core, made by Fused::into_future.
+    pub fn fuse(self) -> Fused<T, Identity> {
+        return Fused {
+            future: self,
+            stage: Identity,
+        };
     }
 
-    #[test]
-    fn test_future_then_val() {
-        let mut future = Future::new(Try::new_value(0));
-        let res = future.then_val(|try| {
-                let v = try.value().unwrap();
-                return v + 1;
+    /// Erase the value's type, so futures of different types can be kept
+    /// together, e.g. in a Vec<BoxedFuture>. See BoxedFuture::downcast.
+    pub fn boxed(mut self) -> BoxedFuture
+        where T: Send + 'static
+    {
+        return match self.then_try(|try| try.map(|val| Box::new(val) as Box<Any + Send>)) {
+            Ok(f) => f,
+            Err(err) => Future::new(Try::new_error(err)),
+        };
+    }
+}
+
+/// A future whose value's type has been erased by Future::boxed.
+pub type BoxedFuture = Future<Box<Any + Send>>;
+
+impl Future<Box<Any + Send>> {
+    /// Recover the type erased by Future::boxed. The result fails if the
+    /// value turns out not to be a `T`.
+    pub fn downcast<T>(mut self) -> Future<T>
+        where T: Send + 'static
+    {
+        let typed = self.then_try(|try| {
+            try.and_then(|val| {
+                match val.downcast::<T>() {
+                    Ok(val) => Try::new_value(*val),
+                    Err(_) => {
+                        Try::new_error(Error::new(ErrorKind::InvalidInput,
+                                                  "BoxedFuture value is not of the requested \
+                                                   type"))
+                    }
+                }
             })
-            .unwrap()
-            .value()
-            .unwrap();
-        assert_eq!(res, 1);
+        });
+        return match typed {
+            Ok(f) => f,
+            Err(err) => Future::new(Try::new_error(err)),
+        };
+    }
+}
+
+/// The body of then's callback: unless `try` failed, fulfill `p` with the
+/// result of the future `func` returns.
+fn chain<T, U, E, F>(try: Try<T, E>, func: F, p: Promise<U, E>)
+    where F: FnOnce(Try<T, E>) -> Future<U, E> + Send + 'static,
+          U: Send + 'static,
+          E: From<Error> + From<TryUsageError> + Send + 'static
+{
+    if try.has_error() {
+        p.set_error(try);
+    } else {
+        // A panic in func fails the downstream future
+        let f2 = Try::with_panic(move || func(try));
+        if f2.has_error() {
+            p.set_error(f2.map_error(E::from));
+            return;
+        }
+        let mut f2 = f2.value().unwrap();
+        f2.set_callback(move |try2| {
+            p.set_try(try2);
+        });
+    }
+}
+
+impl<T> Future<Future<T>>
+    where T: Send + 'static
+{
+    /// Settles with the inner future's result once both have settled. An
+    /// error from either future is passed through.
+    pub fn flatten(mut self) -> Future<T> {
+        let flat = self.then(|try| {
+            match try.value() {
+                Ok(inner) => inner,
+                Err(err) => Future::new(Try::new_error(err)),
+            }
+        });
+        return match flat {
+            Ok(f) => f,
+            Err(err) => Future::new(Try::new_error(err)),
+        };
+    }
+}
+
+/// A future that can be cloned and read any number of times, each read
+/// getting a copy of the one result, see Future::share. Clones share the
+/// underlying future and the copy of its result kept for later readers.
+pub struct SharedFuture<T> {
+    shared: Arc<SharedPromise<T>>,
+}
+
+impl<T> Clone for SharedFuture<T> {
+    fn clone(&self) -> SharedFuture<T> {
+        SharedFuture { shared: self.shared.clone() }
+    }
+}
+
+impl<T> SharedFuture<T>
+    where T: Clone + Send + 'static
+{
+    /// A Future for a copy of the result, to chain on like any other.
+    pub fn get_future(&self) -> Future<T> {
+        return match self.shared.get_future() {
+            Ok(f) => f,
+            Err(err) => Future::new(Try::new_error(err)),
+        };
+    }
+
+    pub fn is_ready(&self) -> bool {
+        return self.shared.is_fulfilled();
+    }
+
+    /// A copy of the result if it is ready, like Future::value.
+    pub fn value(&self) -> Result<T, Error> {
+        if !self.is_ready() {
+            return Err(FutureError::NotReady.into());
+        }
+        return self.get_future().value();
+    }
+
+    /// Block until the result is set, then return a copy of it.
+    pub fn wait(&self) -> Result<T, Error> {
+        return self.get_future().wait();
+    }
+}
+
+/// One step of a Fused run, turning the Try it's given into the next.
+pub trait Stage<T> {
+    type Output;
+
+    fn run(self, try: Try<T>) -> Try<Self::Output>;
+}
+
+/// The empty run Future::fuse starts with.
+pub struct Identity;
+
+impl<T> Stage<T> for Identity {
+    type Output = T;
+
+    fn run(self, try: Try<T>) -> Try<T> {
+        return try;
+    }
+}
+
+/// `stage` followed by a then_val callback.
+pub struct ThenVal<S, F> {
+    stage: S,
+    func: F,
+}
+
+impl<T, S, F, U> Stage<T> for ThenVal<S, F>
+    where S: Stage<T>,
+          F: FnOnce(Try<S::Output>) -> U
+{
+    type Output = U;
+
+    fn run(self, try: Try<T>) -> Try<U> {
+        let try = self.stage.run(try);
+        if try.has_error() {
+            return Try::new_error(try.get_error());
+        }
+        return Try::new_value((self.func)(try));
+    }
+}
+
+/// A future plus synchronous stages still to run on its result, see
+/// Future::fuse. The stages are nested in one value rather than each
+/// getting a Core and a boxed callback, and nothing runs until
+/// into_future.
+pub struct Fused<T, S> {
+    future: Future<T>,
+    stage: S,
+}
+
+impl<T, S> Fused<T, S>
+    where S: Stage<T> + Send + 'static,
+          T: Send + 'static
+{
+    /// Like Future::then_val, run `func` on the result of the stages so far.
+    pub fn then_val<F, U>(self, func: F) -> Fused<T, ThenVal<S, F>>
+        where F: FnOnce(Try<S::Output>) -> U + Send + 'static
+    {
+        return Fused {
+            future: self.future,
+            stage: ThenVal {
+                stage: self.stage,
+                func: func,
+            },
+        };
+    }
+
+    /// Run the stages as one callback on the future, returning a future for
+    /// the result of the last. A panic in any stage fails it, as then_val.
+    pub fn into_future(self) -> Result<Future<S::Output>, Error>
+        where S::Output: Send + 'static
+    {
+        let mut future = self.future;
+        let stage = self.stage;
+        return future.then_try(move |try| stage.run(try));
+    }
+}
+
+/// Run `func` on `exec` and return a future for its result, like
+/// thread::spawn but on an executor. See Future::defer_value.
+pub fn spawn<T, F>(exec: Arc<Executor + Send + Sync>, func: F) -> Future<T>
+    where F: FnOnce() -> T + Send + 'static,
+          T: Send + 'static
+{
+    return Future::defer_value(exec, func);
+}
+
+/// Collect the values of `futures` in order once all have settled, or the
+/// error of the earliest one that failed. Raising on the result raises on
+/// each of `futures`.
+pub fn collect<T>(futures: Vec<Future<T>>) -> Future<Vec<T>>
+    where T: Send + 'static
+{
+    let n = futures.len();
+    return window(futures, |f| f, n);
+}
+
+/// Like collect, but only `chunk` of `futures` at a time: each chunk is
+/// collected, and the next one only waited on once it has settled. This
+/// caps how many futures collect is tracking at once, at the cost of
+/// waiting on the slowest future of each chunk in turn. Fails with the
+/// error of the first chunk that fails, without waiting on the rest.
+pub fn collect_chunked<T>(futures: Vec<Future<T>>, chunk: usize) -> Future<Vec<T>>
+    where T: Send + 'static
+{
+    assert!(chunk > 0, "collect_chunked needs a chunk of at least one future");
+    let mut p: Promise<Vec<T>> = Promise::new();
+    let f = match p.get_future() {
+        Ok(f) => f,
+        Err(err) => return Future::new(Try::new_error(err)),
+    };
+    let values = Vec::with_capacity(futures.len());
+    collect_chunk(futures.into_iter(), chunk, values, p);
+    return f;
+}
+
+fn collect_chunk<T>(mut futures: vec::IntoIter<Future<T>>,
+                    chunk: usize,
+                    mut values: Vec<T>,
+                    p: Promise<Vec<T>>)
+    where T: Send + 'static
+{
+    let next: Vec<Future<T>> = futures.by_ref().take(chunk).collect();
+    // Like while_do, each chunk starts from a QueuedImmediateExecutor so the
+    // stack stays flat when the chunks have already settled
+    QueuedImmediateExecutor::new().add(Box::new(move || {
+        if next.is_empty() {
+            let _res = p.set_value(values);
+            return;
+        }
+        let mut settled = collect(next);
+        let _res = settled.set_callback(move |try| {
+            match try.value() {
+                Ok(chunk_values) => {
+                    values.extend(chunk_values);
+                    collect_chunk(futures, chunk, values, p);
+                }
+                Err(err) => {
+                    let _res = p.set_error_direct(err);
+                }
+            }
+        });
+    }));
+}
+
+/// Like collect, but without taking `futures`: their results are copied
+/// rather than consumed, so each can still be read with value() later.
+/// Unsafe because the returned future keeps pointers to `futures` past the
+/// borrow: they must outlive it, and mustn't be consumed, read with value()
+/// or given a callback before it settles, as it reads their results from
+/// whichever thread readies the last one.
+pub unsafe fn collect_refs<T>(futures: &mut [Future<T>]) -> Future<Vec<T>>
+    where T: Clone + Send + Sync + 'static
+{
+    if futures.is_empty() {
+        return Future::new(Try::new_value(Vec::new()));
+    }
+    let mut p: Promise<Vec<T>> = Promise::new();
+    let f = match p.get_future() {
+        Ok(f) => f,
+        Err(err) => return Future::new(Try::new_error(err)),
+    };
+    let state = Arc::new(CollectRefsState {
+        remaining: AtomicUsize::new(futures.len()),
+        futures: futures.iter().map(|future| future as *const Future<T>).collect(),
+        promise: SpinMutex::new(Some(p)),
+    });
+    for future in futures.iter_mut() {
+        let state = state.clone();
+        future.on_ready(move || {
+            if state.remaining.fetch_sub(1, Ordering::AcqRel) == 1 {
+                collect_refs_settled(&state);
+            }
+        });
+    }
+    return f;
+}
+
+struct CollectRefsState<T> {
+    remaining: AtomicUsize,
+    /// The borrowed futures, which collect_refs' caller keeps alive
+    futures: Vec<*const Future<T>>,
+    promise: SpinMutex<Option<Promise<Vec<T>>>>,
+}
+
+/// The futures are only peeked at, and cloned from, once they are all ready,
+/// which collect_refs' caller promises not to race with.
+unsafe impl<T: Send + Sync> Send for CollectRefsState<T> {}
+unsafe impl<T: Send + Sync> Sync for CollectRefsState<T> {}
+
+/// Copy every result into the promise, or the first error in order.
+fn collect_refs_settled<T>(state: &CollectRefsState<T>)
+    where T: Clone
+{
+    let p = match state.promise.lock().take() {
+        Some(p) => p,
+        None => return,
+    };
+    let mut values = Vec::with_capacity(state.futures.len());
+    for &future in &state.futures {
+        match unsafe { (*future).peek() } {
+            Some(try) => {
+                match try.ok() {
+                    Some(val) => values.push(val.clone()),
+                    None => {
+                        let _res = p.set_error_direct(clone_error(try.err().unwrap()));
+                        return;
+                    }
+                }
+            }
+            None => {
+                let _res = p.set_error_direct(FutureError::NotReady.into());
+                return;
+            }
+        }
+    }
+    let _res = p.set_value(values);
+}
+
+struct OrState<T> {
+    /// Set once either future has failed
+    failed: AtomicBool,
+    promise: SpinMutex<Option<Promise<T>>>,
+}
+
+/// The first value wins, and an error only gets through if the other
+/// future already failed.
+fn or_settled<T>(state: &OrState<T>, try: Try<T>) {
+    if try.has_error() && !state.failed.swap(true, Ordering::AcqRel) {
+        return;
+    }
+    let p = state.promise.lock().take();
+    if let Some(p) = p {
+        let _res = p.set_try(try);
+    }
+}
+
+/// Join two futures of different types, settling once both have. Fails
+/// with the first future's error if both fail. Raising on the result
+/// raises on both.
+pub fn collect_tuple2<A, B>(mut a: Future<A>, mut b: Future<B>) -> Future<(A, B)>
+    where A: Send + 'static,
+          B: Send + 'static
+{
+    let mut p: Promise<(A, B)> = Promise::new();
+    let f = match p.get_future() {
+        Ok(f) => f,
+        Err(err) => return Future::new(Try::new_error(err)),
+    };
+    let state = Arc::new(SpinMutex::new(Tuple2State {
+        a: None,
+        b: None,
+        promise: None,
+    }));
+    let a_state = state.clone();
+    if let Err(err) = a.set_callback(move |try| {
+        a_state.lock().a = Some(try);
+        tuple2_settled(&a_state);
+    }) {
+        state.lock().a = Some(Try::new_error(err));
+    }
+    let b_state = state.clone();
+    if let Err(err) = b.set_callback(move |try| {
+        b_state.lock().b = Some(try);
+        tuple2_settled(&b_state);
+    }) {
+        state.lock().b = Some(Try::new_error(err));
+    }
+    // The handler must be Sync, and a Future isn't
+    let children = SpinMutex::new((a, b));
+    p.set_interrupt_handler(move |err| {
+        let children = children.lock();
+        children.0.raise(clone_error(err));
+        children.1.raise(clone_error(err));
+    });
+    state.lock().promise = Some(p);
+    // Both may have settled before the promise was in place
+    tuple2_settled(&state);
+    return f;
+}
+
+/// Like collect_tuple2, for three futures.
+pub fn collect_tuple3<A, B, C>(a: Future<A>, b: Future<B>, c: Future<C>) -> Future<(A, B, C)>
+    where A: Send + 'static,
+          B: Send + 'static,
+          C: Send + 'static
+{
+    let res = collect_tuple2(collect_tuple2(a, b), c)
+        .then_try(|try| try.map(|((a, b), c)| (a, b, c)));
+    return match res {
+        Ok(f) => f,
+        Err(err) => Future::new(Try::new_error(err)),
+    };
+}
+
+struct Tuple2State<A, B> {
+    a: Option<Try<A>>,
+    b: Option<Try<B>>,
+    promise: Option<Promise<(A, B)>>,
+}
+
+fn tuple2_settled<A, B>(state: &SpinMutex<Tuple2State<A, B>>) {
+    let (p, a, b) = {
+        let mut state = state.lock();
+        if state.a.is_none() || state.b.is_none() || state.promise.is_none() {
+            return;
+        }
+        (state.promise.take().unwrap(), state.a.take().unwrap(), state.b.take().unwrap())
+    };
+    let _res = match (a.value(), b.value()) {
+        (Ok(a), Ok(b)) => p.set_value((a, b)),
+        (Err(err), _) | (_, Err(err)) => p.set_error_direct(err),
+    };
+}
+
+/// A future that is already done, with nothing to deliver.
+pub fn make_unit_future() -> Future<()> {
+    return Future::new(Try::new_value(()));
+}
+
+/// Call `func` on each of `inputs`, keeping at most `n` of the returned
+/// futures outstanding at once and starting the next as each settles. The
+/// values are collected in input order, or the result is the error of the
+/// earliest input that failed, once all have settled. Raising on the result
+/// raises on every future started, including those started afterwards. Like
+/// while_do's iterations, each input is started on a QueuedImmediateExecutor
+/// so that inputs which are already done don't nest.
+pub fn window<T, U, F>(inputs: Vec<T>, func: F, n: usize) -> Future<Vec<U>>
+    where F: Fn(T) -> Future<U> + Send + Sync + 'static,
+          T: Send + 'static,
+          U: Send + 'static
+{
+    let mut p: Promise<Vec<U>> = Promise::new();
+    let f = match p.get_future() {
+        Ok(f) => f,
+        Err(err) => return Future::new(Try::new_error(err)),
+    };
+    let count = inputs.len();
+    if count == 0 {
+        let _res = p.set_value(Vec::new());
+        return f;
+    }
+    let state = Arc::new(WindowState {
+        func: func,
+        inner: SpinMutex::new(WindowInner {
+            pending: inputs.into_iter().enumerate().collect(),
+            results: (0..count).map(|_| None).collect(),
+            remaining: count,
+            promise: None,
+            children: Vec::new(),
+            interrupt: None,
+        }),
+    });
+    let handler_state = state.clone();
+    p.set_interrupt_handler(move |err| window_raise(&handler_state, err));
+    state.inner.lock().promise = Some(p);
+    for _ in 0..cmp::max(n, 1) {
+        window_next(state.clone());
+    }
+    return f;
+}
+
+struct WindowInner<T, U> {
+    /// Inputs not yet started, with their index in the output
+    pending: VecDeque<(usize, T)>,
+    results: Vec<Option<Try<U>>>,
+    /// Inputs that haven't settled yet
+    remaining: usize,
+    promise: Option<Promise<Vec<U>>>,
+    /// Futures started so far, so that an interrupt can be passed on
+    children: Vec<Future<U>>,
+    /// The kind and message of the interrupt raised on the result, if any
+    interrupt: Option<(ErrorKind, String)>,
+}
+
+struct WindowState<T, U, F> {
+    func: F,
+    inner: SpinMutex<WindowInner<T, U>>,
+}
+
+fn window_next<T, U, F>(state: Arc<WindowState<T, U, F>>)
+    where F: Fn(T) -> Future<U> + Send + Sync + 'static,
+          T: Send + 'static,
+          U: Send + 'static
+{
+    QueuedImmediateExecutor::new().add(Box::new(move || {
+        let next = state.inner.lock().pending.pop_front();
+        if let Some((idx, input)) = next {
+            let mut future = (state.func)(input);
+            let callback_state = state.clone();
+            let res = future.set_callback(move |try| {
+                window_settled(&callback_state, idx, try);
+            });
+            if let Err(err) = res {
+                window_settled(&state, idx, Try::new_error(err));
+            }
+            let mut child = Some(future);
+            let interrupt = {
+                let mut inner = state.inner.lock();
+                if inner.interrupt.is_none() {
+                    inner.children.push(child.take().unwrap());
+                }
+                inner.interrupt.clone()
+            };
+            // Started after the result was interrupted, pass it straight on
+            if let (Some(child), Some((kind, msg))) = (child, interrupt) {
+                child.raise(Error::new(kind, msg));
+            }
+        }
+    }));
+}
+
+fn window_raise<T, U, F>(state: &WindowState<T, U, F>, err: &Error) {
+    let children = {
+        let mut inner = state.inner.lock();
+        inner.interrupt = Some((err.kind(), err.to_string()));
+        mem::replace(&mut inner.children, Vec::new())
+    };
+    // Raise outside the lock, a child's handler may well settle it
+    for child in children {
+        child.raise(clone_error(err));
+    }
+}
+
+fn window_settled<T, U, F>(state: &Arc<WindowState<T, U, F>>, idx: usize, try: Try<U>)
+    where F: Fn(T) -> Future<U> + Send + Sync + 'static,
+          T: Send + 'static,
+          U: Send + 'static
+{
+    let finished = {
+        let mut inner = state.inner.lock();
+        inner.results[idx] = Some(try);
+        inner.remaining -= 1;
+        if inner.remaining == 0 {
+            Some((inner.promise.take().unwrap(), mem::replace(&mut inner.results, Vec::new())))
+        } else {
+            None
+        }
+    };
+    let (p, results) = match finished {
+        Some(finished) => finished,
+        None => return window_next(state.clone()),
+    };
+    let mut values = Vec::with_capacity(results.len());
+    for result in results {
+        match result.unwrap().value() {
+            Ok(val) => values.push(val),
+            Err(err) => {
+                let _res = p.set_error_direct(err);
+                return;
+            }
+        }
+    }
+    let _res = p.set_value(values);
+}
+
+/// Run `body` over and over for as long as `pred` returns true, starting
+/// each iteration once the previous one has settled. Iterations are
+/// scheduled on a QueuedImmediateExecutor rather than nested in the last
+/// one's callback, so the stack stays flat however long the loop runs. Fails
+/// with the first error from `body`.
+pub fn while_do<P, F>(pred: P, body: F) -> Future<()>
+    where P: Fn() -> bool + Send + Sync + 'static,
+          F: Fn() -> Future<()> + Send + Sync + 'static
+{
+    let mut p: Promise<()> = Promise::new();
+    let f = match p.get_future() {
+        Ok(f) => f,
+        Err(err) => return Future::new(Try::new_error(err)),
+    };
+    while_do_step(Arc::new((pred, body)), p);
+    return f;
+}
+
+fn while_do_step<P, F>(state: Arc<(P, F)>, p: Promise<()>)
+    where P: Fn() -> bool + Send + Sync + 'static,
+          F: Fn() -> Future<()> + Send + Sync + 'static
+{
+    QueuedImmediateExecutor::new().add(Box::new(move || {
+        if !(state.0)() {
+            let _res = p.set_value(());
+            return;
+        }
+        let mut future = (state.1)();
+        // If this fails p is dropped with the callback, breaking the promise
+        let _res = future.set_callback(move |try| {
+            if try.has_error() {
+                let _res = p.set_error(try);
+            } else {
+                while_do_step(state, p);
+            }
+        });
+    }));
+}
+
+struct DelayedState<T> {
+    /// The result and the timer each count down once, the last one to
+    /// arrive fulfills the promise
+    remaining: AtomicUsize,
+    result: Mutex<Option<Try<T>>>,
+    promise: SpinMutex<Option<Promise<T>>>,
+}
+
+impl<T> DelayedState<T> {
+    fn arrive(&self) {
+        if self.remaining.fetch_sub(1, Ordering::AcqRel) == 1 {
+            let try = self.result.lock().unwrap().take().unwrap();
+            if let Some(p) = self.promise.lock().take() {
+                let _res = p.set_try(try);
+            }
+        }
+    }
+}
+
+fn retry_attempt<T, F>(factory: Arc<F>, retries: usize, p: Promise<T>)
+    where F: Fn() -> Future<T> + Send + Sync + 'static,
+          T: Send + 'static
+{
+    let mut future = factory();
+    // If this fails p is dropped with the callback, breaking the promise
+    let _res = future.set_callback(move |try| {
+        if try.has_error() && retries > 0 {
+            retry_attempt(factory, retries - 1, p);
+        } else {
+            let _res = p.set_try(try);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::io::{Error, ErrorKind};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::mpsc::channel;
+    use std::time::{Duration, Instant};
+    use test::Bencher;
+
+    use alloc_count::count_allocations;
+    use executor::{ManualExecutor, TimerExecutor};
+    use promise::Promise;
+    use super::{Future, FutureState, collect, collect_chunked, collect_refs, collect_tuple2,
+                collect_tuple3, make_unit_future, spawn, while_do, window};
+    use try::{Try, TryUsageError};
+
+
+    #[test]
+    fn test_future_value() {
+        let mut future = Future::new(Try::new_value(0));
+        assert_eq!(future.value().unwrap(), 0);
+    }
+
+    #[bench]
+    fn bench_constant_future(b: &mut Bencher) {
+        b.iter(|| {
+            let future = Future::new(Try::new_value(0));
+        })
+    }
+
+    /// A 10-stage then_val chain, each stage with its own callback and
+    /// downstream Core
+    fn then_val_10() -> usize {
+        let mut p: Promise<usize> = Promise::new();
+        let mut f = p.get_future().unwrap();
+        for _ in 0..10 {
+            f = f.then_val(|try| try.value().unwrap() + 1).unwrap();
+        }
+        p.set_value(0).unwrap();
+        return f.value().unwrap();
+    }
+
+    /// The same chain as then_val_10, fused into one callback and one
+    /// downstream Core rather than ten of each
+    fn fused_then_val_10() -> usize {
+        fn step(try: Try<usize>) -> usize {
+            try.value().unwrap() + 1
+        }
+        let mut p: Promise<usize> = Promise::new();
+        let mut f = p.get_future()
+            .unwrap()
+            .fuse()
+            .then_val(step)
+            .then_val(step)
+            .then_val(step)
+            .then_val(step)
+            .then_val(step)
+            .then_val(step)
+            .then_val(step)
+            .then_val(step)
+            .then_val(step)
+            .then_val(step)
+            .into_future()
+            .unwrap();
+        p.set_value(0).unwrap();
+        return f.value().unwrap();
+    }
+
+    #[bench]
+    fn bench_then_val_10(b: &mut Bencher) {
+        b.iter(then_val_10)
+    }
+
+    #[bench]
+    fn bench_fused_then_val_10(b: &mut Bencher) {
+        b.iter(fused_then_val_10)
+    }
+
+    #[test]
+    fn test_fused_then_val_allocations() {
+        let (unfused, res) = count_allocations(then_val_10);
+        assert_eq!(res, 10);
+        let (fused, res) = count_allocations(fused_then_val_10);
+        assert_eq!(res, 10);
+        // Unfused, each stage after the first adds at least its own Core and
+        // callback to what the fused chain allocates
+        assert!(unfused >= fused + 2 * 9,
+                "{} allocations unfused, {} fused",
+                unfused,
+                fused);
+    }
+
+    #[test]
+    fn test_future_fuse() {
+        let mut p: Promise<usize> = Promise::new();
+        let mut f = p.get_future()
+            .unwrap()
+            .fuse()
+            .then_val(|try| try.value().unwrap() + 1)
+            .then_val(|try| try.value().unwrap().to_string())
+            .then_val(|try| try.value().unwrap() + "!")
+            .into_future()
+            .unwrap();
+        // Nothing runs until there is a result
+        assert!(f.peek().is_none());
+        p.set_value(1).unwrap();
+        assert_eq!(f.value().unwrap(), "2!");
+
+        // An error skips the stages, a panic fails the rest of the run
+        let mut f = Future::<usize>::new(Try::new_error(Error::new(ErrorKind::NotFound, "missing")))
+            .fuse()
+            .then_val(|_| -> usize { panic!("shouldn't run on an error") })
+            .into_future()
+            .unwrap();
+        assert_eq!(f.value().unwrap_err().kind(), ErrorKind::NotFound);
+        let mut f = Future::new(Try::new_value(1))
+            .fuse()
+            .then_val(|_| -> usize { panic!("stage failed") })
+            .then_val(|try| try.value().unwrap() + 1)
+            .into_future()
+            .unwrap();
+        assert_eq!(f.value().unwrap_err().to_string(), "stage failed");
+    }
+
+    #[test]
+    fn test_future_debug() {
+        let future = Future::new(Try::new_value(0));
+        assert_eq!(format!("{:?}", future), "Future { state: OnlyResult, has_result: true }");
+    }
+
+    #[test]
+    fn test_future_state() {
+        let mut p: Promise<usize> = Promise::new();
+        let f = p.get_future().unwrap();
+        assert_eq!(f.state(), FutureState::Start);
+        p.set_value(1).unwrap();
+        assert_eq!(f.state(), FutureState::OnlyResult);
+
+        let mut p: Promise<usize> = Promise::new();
+        let mut f = p.get_future().unwrap();
+        f.set_callback(|_| {}).unwrap();
+        assert_eq!(f.state(), FutureState::OnlyCallback);
+        p.set_value(1).unwrap();
+        assert_eq!(f.state(), FutureState::Done);
+    }
+
+    #[test]
+    fn test_future_then() {
+        let mut future = Future::new(Try::new_value(0));
+        let res = future.then(|try| {
+                let v = try.value().unwrap();
+                return Future::new(Try::new_value(v + 1));
+            })
+            .unwrap()
+            .value()
+            .unwrap();
+        assert_eq!(res, 1);
+    }
+
+    #[test]
+    fn test_future_then_val() {
+        let mut future = Future::new(Try::new_value(0));
+        let res = future.then_val(|try| {
+                let v = try.value().unwrap();
+                return v + 1;
+            })
+            .unwrap()
+            .value()
+            .unwrap();
+        assert_eq!(res, 1);
+    }
+
+    #[test]
+    fn test_future_raise() {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let mut p: Promise<usize> = Promise::new();
+        let f = p.get_future().unwrap();
+        p.set_interrupt_handler(|err| {
+            assert_eq!(err.kind(), ErrorKind::Interrupted);
+            COUNTER.fetch_add(1, Ordering::SeqCst);
+        });
+        f.raise(Error::new(ErrorKind::Interrupted, "no longer needed"));
+        assert_eq!(COUNTER.load(Ordering::SeqCst), 1);
+        // Only the first raise is delivered
+        f.raise(Error::new(ErrorKind::Interrupted, "no longer needed"));
+        assert_eq!(COUNTER.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_future_raise_after_result() {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let mut p: Promise<usize> = Promise::new();
+        let mut f = p.get_future().unwrap();
+        p.set_interrupt_handler(|_| {
+            COUNTER.fetch_add(1, Ordering::SeqCst);
+        });
+        p.set_value(1).unwrap();
+        f.raise(Error::new(ErrorKind::Interrupted, "no longer needed"));
+        assert_eq!(COUNTER.load(Ordering::SeqCst), 0);
+        assert_eq!(f.value().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_future_peek() {
+        let mut p: Promise<usize> = Promise::new();
+        let mut f = p.get_future().unwrap();
+        assert!(f.peek().is_none());
+        p.set_value(3).unwrap();
+        assert_eq!(f.peek().unwrap().ok(), Some(&3));
+        assert_eq!(f.peek().unwrap().ok(), Some(&3));
+        assert_eq!(f.value().unwrap(), 3);
+    }
+
+    #[test]
+    fn test_future_then_val_panic() {
+        let mut future = Future::new(Try::new_value(0));
+        let mut f = future.then_val(|_| -> usize { panic!("callback failed") }).unwrap();
+        assert_eq!(f.value().unwrap_err().to_string(), "callback failed");
+    }
+
+    #[test]
+    fn test_future_then_panic() {
+        let mut future = Future::new(Try::new_value(0));
+        let mut f = future.then(|_| -> Future<usize> { panic!("callback failed") }).unwrap();
+        assert_eq!(f.value().unwrap_err().to_string(), "callback failed");
+    }
+
+    #[test]
+    fn test_future_then_panic_breaks_promise() {
+        use error::FutureError;
+
+        let mut p: Promise<usize> = Promise::new();
+        let mut future = p.get_future().unwrap();
+        let mut f = future.then(|_| -> Future<usize> { panic!("callback failed") }).unwrap();
+        // The panic doesn't reach the thread setting the result
+        p.set_value(0).unwrap();
+        let err = f.value().unwrap_err();
+        assert_eq!(FutureError::from_io(&err), Some(FutureError::BrokenPromise));
+        assert_eq!(err.to_string(), "callback failed");
+
+        // Nor does one in a bare callback, which breaks the promise it owns
+        let mut p: Promise<usize> = Promise::new();
+        let mut future = p.get_future().unwrap();
+        let mut downstream: Promise<usize> = Promise::new();
+        let mut f = downstream.get_future().unwrap();
+        future.set_callback(move |_| {
+                let _downstream = downstream;
+                panic!("callback failed");
+            })
+            .unwrap();
+        p.set_value(0).unwrap();
+        let err = f.value().unwrap_err();
+        assert_eq!(FutureError::from_io(&err), Some(FutureError::BrokenPromise));
+    }
+
+    #[test]
+    fn test_future_on_ready() {
+        use microspinlock::spawn_unsafe;
+
+        let mut p: Promise<usize> = Promise::new();
+        let mut f = p.get_future().unwrap();
+        let (tx, rx) = channel();
+        f.on_ready(move || tx.send(()).unwrap());
+        let child = unsafe {
+            spawn_unsafe(move || {
+                p.set_value(4).unwrap();
+            })
+        };
+        rx.recv().unwrap();
+        assert_eq!(f.value().unwrap(), 4);
+        child.join().unwrap();
+    }
+
+    #[test]
+    fn test_future_on_ready_already_set() {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let mut f = Future::new(Try::new_value(4));
+        f.on_ready(|| {
+            COUNTER.fetch_add(1, Ordering::SeqCst);
+        });
+        assert_eq!(COUNTER.load(Ordering::SeqCst), 1);
+        assert_eq!(f.value().unwrap(), 4);
+    }
+
+    #[test]
+    fn test_future_then_try() {
+        let mut future = Future::new(Try::new_value(0));
+        let err = future.then_try(|try| {
+                let v: usize = try.value().unwrap();
+                if v == 0 {
+                    return Try::new_error(Error::new(ErrorKind::InvalidInput, "zero"));
+                }
+                return Try::new_value(v);
+            })
+            .unwrap()
+            .value()
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_future_then_try_recover() {
+        let mut future: Future<usize> =
+            Future::new(Try::new_error(Error::new(ErrorKind::Other, "error")));
+        let res = future.then_try(|try| Try::new_value(try.value_or(5)))
+            .unwrap()
+            .value()
+            .unwrap();
+        assert_eq!(res, 5);
+    }
+
+    #[test]
+    fn test_future_and() {
+        let mut p: Promise<&'static str> = Promise::new();
+        let mut source = Future::new(Try::new_value(1));
+        let mut f = source.and(p.get_future().unwrap()).unwrap();
+        assert!(f.peek().is_none());
+        p.set_value("next").unwrap();
+        assert_eq!(f.value().unwrap(), "next");
+    }
+
+    #[test]
+    fn test_future_and_invalid_next() {
+        use error::FutureError;
+
+        let mut source = Future::new(Try::new_value(1));
+        let mut next: Future<usize> = Future::new(Try::new_value(2));
+        next.detach();
+        let mut f = source.and(next).unwrap();
+        let err = f.value().unwrap_err();
+        assert_eq!(FutureError::from_io(&err), Some(FutureError::NoState));
+    }
+
+    #[test]
+    fn test_future_and_error() {
+        use std::sync::Arc;
+
+        let payload = Arc::new(0);
+        let mut source: Future<i32> =
+            Future::new(Try::new_error(Error::new(ErrorKind::NotFound, "missing")));
+        let mut f = source.and(Future::new(Try::new_value(payload.clone()))).unwrap();
+        assert_eq!(f.value().unwrap_err().kind(), ErrorKind::NotFound);
+        // next's Core, and the value in it, have been freed
+        assert_eq!(Arc::strong_count(&payload), 1);
+    }
+
+    #[test]
+    fn test_future_or() {
+        let failed = |msg| Future::<i32>::new(Try::new_error(Error::new(ErrorKind::Other, msg)));
+
+        // First succeeds
+        let mut p: Promise<i32> = Promise::new();
+        let mut f = Future::new(Try::new_value(1)).or(p.get_future().unwrap());
+        assert_eq!(f.value().unwrap(), 1);
+        p.set_value(2).unwrap();
+
+        // First fails, then second succeeds
+        let mut p: Promise<i32> = Promise::new();
+        let mut f = failed("first").or(p.get_future().unwrap());
+        assert!(f.peek().is_none());
+        p.set_value(2).unwrap();
+        assert_eq!(f.value().unwrap(), 2);
+
+        // Both fail
+        let mut p: Promise<i32> = Promise::new();
+        let mut f = failed("first").or(p.get_future().unwrap());
+        p.set_error_direct(Error::new(ErrorKind::Other, "second")).unwrap();
+        assert_eq!(f.value().unwrap_err().to_string(), "second");
+    }
+
+    #[test]
+    fn test_future_filter() {
+        let mut future = Future::new(Try::new_value(4));
+        let res = future.filter(|v| v % 2 == 0).unwrap().value().unwrap();
+        assert_eq!(res, 4);
+        let mut future = Future::new(Try::new_value(3));
+        let err = future.filter(|v| v % 2 == 0).unwrap().value().unwrap_err();
+        assert_eq!(err.to_string(), "Value predicate failed");
+    }
+
+    #[test]
+    fn test_future_filter_error() {
+        let mut future: Future<usize> =
+            Future::new(Try::new_error(Error::new(ErrorKind::TimedOut, "slow")));
+        let err = future.filter(|_| panic!("predicate shouldn't run on an error"))
+            .unwrap()
+            .value()
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn test_future_unit() {
+        let mut future = Future::new(Try::new_value(3));
+        assert_eq!(future.unit().unwrap().value().unwrap(), ());
+        let mut future: Future<i32> =
+            Future::new(Try::new_error(Error::new(ErrorKind::TimedOut, "slow")));
+        assert_eq!(future.unit().unwrap().value().unwrap_err().kind(), ErrorKind::TimedOut);
+        assert_eq!(make_unit_future().value().unwrap(), ());
+    }
+
+    #[test]
+    fn test_future_map_error() {
+        let mut future: Future<usize> =
+            Future::new(Try::new_error(Error::new(ErrorKind::Other, "slow")));
+        let err = future.map_error(|err| Error::new(ErrorKind::TimedOut, err.to_string()))
+            .unwrap()
+            .value()
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+        assert_eq!(err.to_string(), "slow");
+
+        let mut future = Future::new(Try::new_value(1));
+        let res = future.map_error(|_| panic!("values shouldn't be mapped"))
+            .unwrap()
+            .value()
+            .unwrap();
+        assert_eq!(res, 1);
+    }
+
+    #[test]
+    fn test_future_reduce() {
+        let mut p1: Promise<usize> = Promise::new();
+        let mut p2: Promise<usize> = Promise::new();
+        let futures = vec![Future::new(Try::new_value(1)),
+                           p1.get_future().unwrap(),
+                           p2.get_future().unwrap()];
+        let mut reduced = Future::reduce(futures, String::new(), |acc, v| format!("{}{}", acc, v));
+        // Folded in input order even when settled out of order
+        p2.set_value(3).unwrap();
+        p1.set_value(2).unwrap();
+        assert_eq!(reduced.value().unwrap(), "123");
+    }
+
+    #[test]
+    fn test_future_reduce_error() {
+        let futures = vec![Future::new(Try::new_value(1)),
+                           Future::new(Try::new_error(Error::new(ErrorKind::TimedOut, "slow"))),
+                           Future::new(Try::new_value(3))];
+        let mut reduced = Future::reduce(futures, 0, |acc, v| acc + v);
+        assert_eq!(reduced.value().unwrap_err().kind(), ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn test_future_retry() {
+        static ATTEMPTS: AtomicUsize = AtomicUsize::new(0);
+        let flaky = || {
+            let attempt = ATTEMPTS.fetch_add(1, Ordering::SeqCst);
+            if attempt < 2 {
+                return Future::new(Try::new_error(Error::new(ErrorKind::Other, "flaky")));
+            }
+            return Future::new(Try::new_value(attempt));
+        };
+        let mut future = Future::retry(flaky, 5);
+        assert_eq!(future.value().unwrap(), 2);
+        assert_eq!(ATTEMPTS.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn test_future_retry_exhausted() {
+        static ATTEMPTS: AtomicUsize = AtomicUsize::new(0);
+        let failing = || {
+            let attempt = ATTEMPTS.fetch_add(1, Ordering::SeqCst);
+            let err = Error::new(ErrorKind::Other, format!("attempt {}", attempt));
+            return Future::new(Try::new_error(err));
+        };
+        let mut future: Future<usize> = Future::retry(failing, 2);
+        assert_eq!(future.value().unwrap_err().to_string(), "attempt 2");
+        assert_eq!(ATTEMPTS.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn test_future_fallback_to() {
+        let mut primary: Future<usize> =
+            Future::new(Try::new_error(Error::new(ErrorKind::Other, "primary failed")));
+        let mut p: Promise<usize> = Promise::new();
+        let backup = p.get_future().unwrap();
+        let mut f = primary.fallback_to(backup).unwrap();
+        p.set_value(2).unwrap();
+        assert_eq!(f.value().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_future_fallback_to_invalid_backup() {
+        let mut primary: Future<usize> =
+            Future::new(Try::new_error(Error::new(ErrorKind::Other, "primary failed")));
+        let mut backup = Future::new(Try::new_value(2));
+        backup.detach();
+        let mut f = primary.fallback_to(backup).unwrap();
+        assert_eq!(f.value().unwrap_err().to_string(), "primary failed");
+    }
+
+    #[test]
+    fn test_future_fallback_to_unused() {
+        let mut primary = Future::new(Try::new_value(1));
+        let backup = Future::new(Try::new_value(2));
+        assert_eq!(primary.fallback_to(backup).unwrap().value().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_future_tap() {
+        static OBSERVED: AtomicUsize = AtomicUsize::new(0);
+        let mut future = Future::new(Try::new_value(3));
+        let res = future.tap(|try| {
+                OBSERVED.store(*try.ok().unwrap(), Ordering::SeqCst);
+            })
+            .unwrap()
+            .value()
+            .unwrap();
+        assert_eq!(OBSERVED.load(Ordering::SeqCst), 3);
+        assert_eq!(res, 3);
+    }
+
+    #[test]
+    fn test_future_inspect() {
+        static INSPECTED: AtomicUsize = AtomicUsize::new(0);
+        let mut future = Future::new(Try::new_value(3));
+        let res = future.inspect(|val| {
+                INSPECTED.fetch_add(*val, Ordering::SeqCst);
+            })
+            .unwrap()
+            .value()
+            .unwrap();
+        assert_eq!(INSPECTED.load(Ordering::SeqCst), 3);
+        assert_eq!(res, 3);
+
+        let mut future: Future<usize> = Future::new(Try::new_error(Error::new(ErrorKind::Other,
+                                                                              "failed")));
+        let mut f = future.inspect(|val| {
+                INSPECTED.fetch_add(*val, Ordering::SeqCst);
+            })
+            .unwrap();
+        assert_eq!(f.value().unwrap_err().to_string(), "failed");
+        assert_eq!(INSPECTED.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn test_future_inspect_error() {
+        static LOGGED: AtomicUsize = AtomicUsize::new(0);
+        let mut future = Future::new(Try::new_value(3));
+        let mut f = future.inspect_error(|_| {
+                LOGGED.fetch_add(1, Ordering::SeqCst);
+            })
+            .unwrap();
+        assert_eq!(f.value().unwrap(), 3);
+        assert_eq!(LOGGED.load(Ordering::SeqCst), 0);
+
+        let mut future: Future<usize> = Future::new(Try::new_error(Error::new(ErrorKind::TimedOut,
+                                                                              "failed")));
+        let err = future.inspect_error(|err| {
+                assert_eq!(err.to_string(), "failed");
+                LOGGED.fetch_add(1, Ordering::SeqCst);
+            })
+            .unwrap()
+            .value()
+            .unwrap_err();
+        assert_eq!(LOGGED.load(Ordering::SeqCst), 1);
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn test_future_on_error_kind() {
+        let mut future: Future<usize> =
+            Future::new(Try::new_error(Error::new(ErrorKind::TimedOut, "slow")));
+        let mut f = future.on_error_kind(ErrorKind::TimedOut, |err| {
+                assert_eq!(err.to_string(), "slow");
+                0
+            })
+            .unwrap();
+        assert_eq!(f.value().unwrap(), 0);
+
+        let mut future: Future<usize> =
+            Future::new(Try::new_error(Error::new(ErrorKind::NotFound, "missing")));
+        let err = future.on_error_kind(ErrorKind::TimedOut, |_| 0)
+            .unwrap()
+            .value()
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        assert_eq!(err.to_string(), "missing");
+
+        let mut future = Future::new(Try::new_value(3));
+        let mut f = future.on_error_kind(ErrorKind::TimedOut, |_| 0).unwrap();
+        assert_eq!(f.value().unwrap(), 3);
+    }
+
+    #[test]
+    fn test_future_within() {
+        let mut p: Promise<usize> = Promise::new();
+        let (tx, rx) = channel();
+        p.get_future()
+            .unwrap()
+            .within(Duration::from_secs(60))
+            .unwrap()
+            .tap(move |try| {
+                tx.send(*try.ok().unwrap()).unwrap();
+            })
+            .unwrap();
+        p.set_value(1).unwrap();
+        assert_eq!(rx.recv().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_future_within_timeout() {
+        let mut p: Promise<usize> = Promise::new();
+        let (tx, rx) = channel();
+        p.get_future()
+            .unwrap()
+            .within(Duration::from_millis(10))
+            .unwrap()
+            .tap(move |try| {
+                tx.send(try.err().unwrap().kind()).unwrap();
+            })
+            .unwrap();
+        assert_eq!(rx.recv().unwrap(), ErrorKind::TimedOut);
+        // Arriving late is harmless, the timeout already won
+        p.set_value(1).unwrap();
+    }
+
+    #[test]
+    fn test_future_within_many() {
+        use std::cell::Cell;
+
+        // Counts the distinct threads timeouts are delivered on
+        static THREADS: AtomicUsize = AtomicUsize::new(0);
+        thread_local!(static SEEN: Cell<bool> = Cell::new(false));
+
+        let timer = TimerExecutor::new();
+        let (tx, rx) = channel();
+        let mut promises: Vec<Promise<usize>> = Vec::new();
+        for _ in 0..1000 {
+            let mut p = Promise::new();
+            let tx = tx.clone();
+            p.get_future()
+                .unwrap()
+                .within_on(&timer, Duration::from_millis(10))
+                .unwrap()
+                .tap(move |try| {
+                    SEEN.with(|seen| {
+                        if !seen.get() {
+                            seen.set(true);
+                            THREADS.fetch_add(1, Ordering::SeqCst);
+                        }
+                    });
+                    tx.send(try.err().unwrap().kind()).unwrap();
+                })
+                .unwrap();
+            promises.push(p);
+        }
+        for kind in rx.iter().take(1000) {
+            assert_eq!(kind, ErrorKind::TimedOut);
+        }
+        assert_eq!(THREADS.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_future_within_deadline() {
+        let mut p: Promise<usize> = Promise::new();
+        let f = p.get_future()
+            .unwrap()
+            .within_deadline(Instant::now() + Duration::from_secs(60))
+            .unwrap();
+        p.set_value(1).unwrap();
+        assert_eq!(f.wait().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_future_within_past_deadline() {
+        let deadline = Instant::now();
+        let mut p: Promise<usize> = Promise::new();
+        let mut f = p.get_future().unwrap().within_deadline(deadline).unwrap();
+        // Already failed, no need to wait on the timer
+        assert_eq!(f.value().unwrap_err().kind(), ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn test_future_on_timeout() {
+        let mut p: Promise<usize> = Promise::new();
+        let f = p.get_future()
+            .unwrap()
+            .on_timeout(Duration::from_millis(10), || 5)
+            .unwrap();
+        assert_eq!(f.wait().unwrap(), 5);
+    }
+
+    #[test]
+    fn test_future_on_timeout_error() {
+        let mut future: Future<usize> =
+            Future::new(Try::new_error(Error::new(ErrorKind::TimedOut, "upstream")));
+        let f = future.on_timeout(Duration::from_secs(60),
+                        || panic!("fallback shouldn't run on an upstream error"))
+            .unwrap();
+        assert_eq!(f.wait().unwrap_err().to_string(), "upstream");
+    }
+
+    #[test]
+    fn test_future_on_timeout_upstream_within() {
+        use error::FutureError;
+
+        let mut p: Promise<usize> = Promise::new();
+        let f = p.get_future()
+            .unwrap()
+            .within(Duration::from_millis(10))
+            .unwrap()
+            .on_timeout(Duration::from_secs(60),
+                        || panic!("fallback shouldn't run on an upstream timeout"))
+            .unwrap();
+        let err = f.wait().unwrap_err();
+        assert_eq!(FutureError::from_io(&err), Some(FutureError::TimedOut));
+    }
+
+    #[test]
+    fn test_future_completed_within() {
+        let (within, try) = Future::new(Try::new_value(1))
+            .completed_within(Duration::from_secs(60))
+            .unwrap()
+            .value()
+            .unwrap();
+        assert!(within);
+        assert_eq!(try.value().unwrap(), 1);
+
+        let (within, try) = Future::new(Try::new_value(2))
+            .delayed(Duration::from_millis(50))
+            .unwrap()
+            .completed_within(Duration::from_millis(10))
+            .unwrap()
+            .wait()
+            .unwrap();
+        assert!(!within);
+        assert_eq!(try.value().unwrap(), 2);
+
+        // Errors are measured and passed along too
+        let (within, try) = Future::<usize>::new(Try::new_error(Error::new(ErrorKind::Other,
+                                                                           "failed")))
+            .completed_within(Duration::from_secs(60))
+            .unwrap()
+            .value()
+            .unwrap();
+        assert!(within);
+        assert_eq!(try.value().unwrap_err().to_string(), "failed");
+    }
+
+    #[test]
+    fn test_future_delayed() {
+        let start = Instant::now();
+        let (tx, rx) = channel();
+        Future::new(Try::new_value(1))
+            .delayed(Duration::from_millis(20))
+            .unwrap()
+            .tap(move |try| {
+                tx.send(*try.ok().unwrap()).unwrap();
+            })
+            .unwrap();
+        assert_eq!(rx.recv().unwrap(), 1);
+        assert!(start.elapsed() >= Duration::from_millis(20));
+    }
+
+    #[test]
+    fn test_future_wait() {
+        let future = Future::new(Try::new_value(1)).delayed(Duration::from_millis(10)).unwrap();
+        assert_eq!(future.wait().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_future_get() {
+        let future = Future::new(Try::new_value(1)).delayed(Duration::from_millis(10)).unwrap();
+        assert_eq!(future.get(), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "upstream failed")]
+    fn test_future_get_error() {
+        let future: Future<usize> =
+            Future::new(Try::new_error(Error::new(ErrorKind::Other, "upstream failed")));
+        future.get();
+    }
+
+    #[test]
+    fn test_future_unwrap_err() {
+        let mut p: Promise<usize> = Promise::new();
+        let future = p.get_future().unwrap();
+        p.set_error_direct(Error::new(ErrorKind::NotFound, "missing")).unwrap();
+        let err = future.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        assert_eq!(err.to_string(), "missing");
+    }
+
+    #[test]
+    #[should_panic(expected = "called unwrap_err on a future that succeeded")]
+    fn test_future_unwrap_err_success() {
+        Future::new(Try::new_value(1)).unwrap_err();
+    }
+
+    #[test]
+    fn test_future_flatten() {
+        let mut p: Promise<i32> = Promise::new();
+        let nested = Future::new(Try::new_value(p.get_future().unwrap()));
+        let flat = nested.flatten();
+        p.set_value(7).unwrap();
+        assert_eq!(flat.wait().unwrap(), 7);
+
+        let inner: Future<i32> =
+            Future::new(Try::new_error(Error::new(ErrorKind::TimedOut, "inner")));
+        let nested = Future::new(Try::new_value(inner));
+        assert_eq!(nested.flatten().wait().unwrap_err().kind(), ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn test_future_flatten_outer_error() {
+        let nested: Future<Future<i32>> =
+            Future::new(Try::new_error(Error::new(ErrorKind::Other, "outer")));
+        assert_eq!(nested.flatten().wait().unwrap_err().to_string(), "outer");
+    }
+
+    #[test]
+    fn test_future_then_traced() {
+        use error::{ChainError, ErrorOrigin};
+
+        let mut source: Future<i32> =
+            Future::new(Try::new_error(Error::new(ErrorKind::NotFound, "no such key")));
+        let err = source.then_traced(|try| Future::new(Try::new_value(try.value().unwrap() + 1)))
+            .unwrap()
+            .value()
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        assert_eq!(err.to_string(), "source failed: no such key");
+        assert_eq!(ChainError::from_io(&err).unwrap().origin(), ErrorOrigin::Source);
+
+        let mut source = Future::new(Try::new_value(1));
+        let err = source.then_traced(|_| {
+                Future::<i32>::new(Try::new_error(Error::new(ErrorKind::TimedOut, "slow backend")))
+            })
+            .unwrap()
+            .value()
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+        assert_eq!(err.to_string(), "continuation failed: slow backend");
+        assert_eq!(ChainError::from_io(&err).unwrap().origin(), ErrorOrigin::Continuation);
+
+        let mut source = Future::new(Try::new_value(1));
+        assert_eq!(source.then_traced(|try| Future::new(Try::new_value(try.value().unwrap() + 1)))
+                       .unwrap()
+                       .value()
+                       .unwrap(),
+                   2);
+    }
+
+    #[test]
+    fn test_future_transform() {
+        let ok = |try: Try<i32>| try.value().map(|val| val * 2).or_else(|_| Ok(0));
+        let err = |try: Try<i32>| -> Result<i32, Error> {
+            match try.value() {
+                Ok(val) => Err(Error::new(ErrorKind::InvalidData, format!("bad value {}", val))),
+                Err(err) => Err(Error::new(ErrorKind::InvalidData, format!("wrapped {}", err))),
+            }
+        };
+        let failed = || Future::new(Try::new_error(Error::new(ErrorKind::Other, "failed")));
+
+        // value -> Ok
+        assert_eq!(Future::new(Try::new_value(2)).transform(ok).unwrap().value().unwrap(), 4);
+        // value -> Err
+        assert_eq!(Future::new(Try::new_value(2)).transform(err).unwrap().value().unwrap_err()
+                       .to_string(),
+                   "bad value 2");
+        // error -> Ok
+        assert_eq!(failed().transform(ok).unwrap().value().unwrap(), 0);
+        // error -> Err
+        let res = failed().transform(err).unwrap().value().unwrap_err();
+        assert_eq!(res.kind(), ErrorKind::InvalidData);
+        assert_eq!(res.to_string(), "wrapped failed");
+    }
+
+    #[test]
+    fn test_future_moved_into_combinators() {
+        let mut p: Promise<i32> = Promise::new();
+        let backup = p.get_future().unwrap();
+        let mut primary: Future<i32> =
+            Future::new(Try::new_error(Error::new(ErrorKind::Other, "primary failed")));
+        // backup is moved into fallback_to's callback, and the nested future
+        // into the outer one
+        let fallback = primary.fallback_to(backup).unwrap();
+        let nested = Future::new(Try::new_value(fallback));
+        let mut flat = nested.flatten();
+        p.set_value(3).unwrap();
+        assert_eq!(flat.value().unwrap(), 3);
+        // Each Core is detached once by whichever handle is left
+        drop(flat);
+        drop(primary);
+        drop(p);
+    }
+
+    #[test]
+    fn test_future_ready_or_else() {
+        assert_eq!(Future::new(Try::new_value(1)).ready_or_else(|| 2), 1);
+
+        let failed: Future<i32> =
+            Future::new(Try::new_error(Error::new(ErrorKind::Other, "failed")));
+        assert_eq!(failed.ready_or_else(|| 2), 2);
+
+        let mut p: Promise<i32> = Promise::new();
+        assert_eq!(p.get_future().unwrap().ready_or_else(|| 3), 3);
+        p.set_value(1).unwrap();
+    }
+
+    #[test]
+    fn test_future_split() {
+        let mut p: Promise<String> = Promise::new();
+        let (first, mut second) = p.get_future().unwrap().split();
+        assert!(first.peek().is_none());
+        assert!(second.peek().is_none());
+        p.set_value(String::from("hello")).unwrap();
+        // Consuming one half leaves the other untouched
+        assert_eq!(first.wait().unwrap(), "hello");
+        assert_eq!(second.value().unwrap(), "hello");
+    }
+
+    #[test]
+    fn test_future_share() {
+        use error::FutureError;
+
+        let mut p: Promise<String> = Promise::new();
+        let shared = p.get_future().unwrap().share();
+        let clones = vec![shared.clone(), shared.clone(), shared.clone()];
+        let err = shared.value().unwrap_err();
+        assert_eq!(FutureError::from_io(&err), Some(FutureError::NotReady));
+        p.set_value(String::from("hello")).unwrap();
+        for clone in &clones {
+            assert!(clone.is_ready());
+            assert_eq!(clone.value().unwrap(), "hello");
+            // Reading again gets another copy
+            assert_eq!(clone.wait().unwrap(), "hello");
+        }
+        let mut f = shared.get_future();
+        assert_eq!(f.then_val(|try| try.value().unwrap().len()).unwrap().value().unwrap(),
+                   5);
+    }
+
+    #[test]
+    fn test_window() {
+        use std::sync::{Arc, Mutex};
+
+        static IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+        static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+        let outstanding: Arc<Mutex<Vec<(usize, Promise<usize>)>>> =
+            Arc::new(Mutex::new(Vec::new()));
+        let started = outstanding.clone();
+        let f = window((0..20).collect(),
+                       move |input| {
+                           let in_flight = IN_FLIGHT.fetch_add(1, Ordering::SeqCst) + 1;
+                           if in_flight > PEAK.load(Ordering::SeqCst) {
+                               PEAK.store(in_flight, Ordering::SeqCst);
+                           }
+                           let mut p = Promise::new();
+                           let f = p.get_future().unwrap();
+                           started.lock().unwrap().push((input, p));
+                           return f;
+                       },
+                       4);
+        // Settle the newest first, so inputs finish out of order
+        loop {
+            let next = outstanding.lock().unwrap().pop();
+            match next {
+                Some((input, p)) => {
+                    IN_FLIGHT.fetch_sub(1, Ordering::SeqCst);
+                    p.set_value(input * 2).unwrap();
+                }
+                None => break,
+            }
+        }
+        let expected: Vec<usize> = (0..20).map(|x| x * 2).collect();
+        assert_eq!(f.wait().unwrap(), expected);
+        assert_eq!(PEAK.load(Ordering::SeqCst), 4);
+    }
+
+    #[test]
+    fn test_window_stack_depth() {
+        // Lowest and highest stack addresses seen from func
+        static LOW: AtomicUsize = AtomicUsize::new(!0);
+        static HIGH: AtomicUsize = AtomicUsize::new(0);
+
+        let f = window((0..100000).collect(),
+                       |input: usize| {
+                           let local = 0;
+                           let addr = &local as *const i32 as usize;
+                           if addr < LOW.load(Ordering::SeqCst) {
+                               LOW.store(addr, Ordering::SeqCst);
+                           }
+                           if addr > HIGH.load(Ordering::SeqCst) {
+                               HIGH.store(addr, Ordering::SeqCst);
+                           }
+                           return Future::new(Try::new_value(input));
+                       },
+                       1);
+        assert_eq!(f.wait().unwrap().len(), 100000);
+        assert!(HIGH.load(Ordering::SeqCst) - LOW.load(Ordering::SeqCst) < 4096);
+    }
+
+    #[test]
+    fn test_window_error() {
+        let f = window(vec![1, 2, 3],
+                       |input| {
+                           if input == 2 {
+                               return Future::new(Try::new_error(Error::new(ErrorKind::Other,
+                                                                            "two")));
+                           }
+                           return Future::new(Try::new_value(input));
+                       },
+                       2);
+        assert_eq!(f.wait().unwrap_err().to_string(), "two");
+    }
+
+    #[test]
+    fn test_while_do() {
+        use std::sync::Arc;
+
+        let count = Arc::new(AtomicUsize::new(0));
+        let pred_count = count.clone();
+        let body_count = count.clone();
+        let f = while_do(move || pred_count.load(Ordering::SeqCst) < 5,
+                         move || {
+                             body_count.fetch_add(1, Ordering::SeqCst);
+                             return Future::new(Try::new_value(()));
+                         });
+        f.wait().unwrap();
+        assert_eq!(count.load(Ordering::SeqCst), 5);
+    }
+
+    #[test]
+    fn test_while_do_stack_depth() {
+        use std::sync::Arc;
+
+        // Lowest and highest stack addresses seen from the loop body
+        static LOW: AtomicUsize = AtomicUsize::new(!0);
+        static HIGH: AtomicUsize = AtomicUsize::new(0);
+
+        let count = Arc::new(AtomicUsize::new(0));
+        let pred_count = count.clone();
+        let body_count = count.clone();
+        let f = while_do(move || pred_count.load(Ordering::SeqCst) < 100000,
+                         move || {
+                             let local = 0;
+                             let addr = &local as *const i32 as usize;
+                             if addr < LOW.load(Ordering::SeqCst) {
+                                 LOW.store(addr, Ordering::SeqCst);
+                             }
+                             if addr > HIGH.load(Ordering::SeqCst) {
+                                 HIGH.store(addr, Ordering::SeqCst);
+                             }
+                             body_count.fetch_add(1, Ordering::SeqCst);
+                             return Future::new(Try::new_value(()));
+                         });
+        f.wait().unwrap();
+        assert_eq!(count.load(Ordering::SeqCst), 100000);
+        assert!(HIGH.load(Ordering::SeqCst) - LOW.load(Ordering::SeqCst) < 4096);
+    }
+
+    #[test]
+    fn test_while_do_error() {
+        let f = while_do(|| true, || {
+            Future::new(Try::new_error(Error::new(ErrorKind::Other, "body failed")))
+        });
+        assert_eq!(f.wait().unwrap_err().to_string(), "body failed");
+    }
+
+    #[test]
+    fn test_collect() {
+        let mut p1: Promise<usize> = Promise::new();
+        let mut p2: Promise<usize> = Promise::new();
+        let f = collect(vec![p1.get_future().unwrap(),
+                             p2.get_future().unwrap(),
+                             Future::new(Try::new_value(3))]);
+        p2.set_value(2).unwrap();
+        p1.set_value(1).unwrap();
+        assert_eq!(f.wait().unwrap(), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn test_collect_chunked() {
+        use executor::Executor;
+        use std::cmp;
+        use std::sync::Arc;
+
+        // Callbacks on these futures are queued on the executor, so what is
+        // pending there is what collect_chunked is waiting on
+        let manual = Arc::new(ManualExecutor::new());
+        let futures = (0..100).map(|i| Future::new_on(Try::new_value(i), manual.clone())).collect();
+        let mut f = collect_chunked(futures, 10);
+        let mut peak = 0;
+        loop {
+            peak = cmp::max(peak, manual.num_pending());
+            if manual.run() == 0 {
+                break;
+            }
+        }
+        assert_eq!(peak, 10);
+        assert_eq!(f.value().unwrap(), (0..100).collect::<Vec<_>>());
+
+        let failed = vec![Future::new(Try::new_value(1)),
+                          Future::new(Try::new_error(Error::new(ErrorKind::Other, "error"))),
+                          Future::new(Try::new_value(3))];
+        assert_eq!(collect_chunked(failed, 2).value().unwrap_err().to_string(), "error");
+    }
+
+    #[test]
+    fn test_collect_refs() {
+        let mut p1: Promise<i32> = Promise::new();
+        let mut p2: Promise<i32> = Promise::new();
+        let mut futures = vec![p1.get_future().unwrap(), p2.get_future().unwrap()];
+        let mut all = unsafe { collect_refs(&mut futures) };
+        p2.set_value(2).unwrap();
+        assert!(all.peek().is_none());
+        p1.set_value(1).unwrap();
+        assert_eq!(all.value().unwrap(), vec![1, 2]);
+        // The originals still hold their results
+        assert_eq!(futures[0].value().unwrap(), 1);
+        assert_eq!(futures[1].value().unwrap(), 2);
+
+        let mut futures: Vec<Future<i32>> =
+            vec![Future::new(Try::new_value(1)),
+                 Future::new(Try::new_error(Error::new(ErrorKind::NotFound, "missing")))];
+        let mut all = unsafe { collect_refs(&mut futures) };
+        assert_eq!(all.value().unwrap_err().kind(), ErrorKind::NotFound);
+        assert_eq!(futures[0].value().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_collect_raise() {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let mut promises: Vec<Promise<usize>> = (0..3).map(|_| Promise::new()).collect();
+        let futures = promises.iter_mut().map(|p| p.get_future().unwrap()).collect();
+        for p in &promises {
+            p.set_interrupt_handler(|err| {
+                assert_eq!(err.kind(), ErrorKind::Interrupted);
+                COUNTER.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+        let f = collect(futures);
+        f.raise(Error::new(ErrorKind::Interrupted, "no longer needed"));
+        assert_eq!(COUNTER.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn test_window_raise_before_start() {
+        use std::sync::{Arc, Mutex};
+
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let outstanding: Arc<Mutex<Vec<Promise<usize>>>> = Arc::new(Mutex::new(Vec::new()));
+        let started = outstanding.clone();
+        let f = window(vec![1, 2],
+                       move |_| {
+                           let mut p = Promise::new();
+                           let f = p.get_future().unwrap();
+                           p.set_interrupt_handler(|_| {
+                               COUNTER.fetch_add(1, Ordering::SeqCst);
+                           });
+                           started.lock().unwrap().push(p);
+                           return f;
+                       },
+                       1);
+        f.raise(Error::new(ErrorKind::Interrupted, "no longer needed"));
+        assert_eq!(COUNTER.load(Ordering::SeqCst), 1);
+        // The second input starts after the raise and sees it too
+        let first = outstanding.lock().unwrap().remove(0);
+        first.set_value(1).unwrap();
+        assert_eq!(COUNTER.load(Ordering::SeqCst), 2);
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum AppError {
+        NotFound(u32),
+        Library(String),
+    }
+
+    impl From<Error> for AppError {
+        fn from(err: Error) -> AppError {
+            AppError::Library(err.to_string())
+        }
+    }
+
+    impl From<TryUsageError> for AppError {
+        fn from(err: TryUsageError) -> AppError {
+            AppError::Library(err.message().to_string())
+        }
+    }
+
+    #[test]
+    fn test_custom_error() {
+        let mut p: Promise<u32, AppError> = Promise::default();
+        let mut f = p.get_future()
+            .unwrap()
+            .then_val(|try| try.value().unwrap() * 2)
+            .unwrap();
+        p.set_error_direct(AppError::NotFound(7)).unwrap();
+        assert_eq!(f.value().unwrap_err(), AppError::NotFound(7));
+
+        let mut p: Promise<u32, AppError> = Promise::default();
+        let f = p.get_future()
+            .unwrap()
+            .then(|try| Future::from_try(Try::new_value(try.value().unwrap() + 1)))
+            .unwrap();
+        p.set_value(1).unwrap();
+        assert_eq!(f.wait().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_custom_error_from_library() {
+        // The library's own errors reach E through its From<io::Error>
+        let mut p: Promise<u32, AppError> = Promise::default();
+        let mut f = p.get_future().unwrap();
+        assert_eq!(f.value().unwrap_err(),
+                   AppError::Library("Future not ready".to_string()));
+        drop(p);
+        assert_eq!(f.value().unwrap_err(),
+                   AppError::Library("Broken Promise".to_string()));
+
+        let mut f: Future<u32, AppError> = Future::from_try(Try::new_value(1));
+        let mut panicked = f.then_val(|_| -> u32 { panic!("boom") }).unwrap();
+        assert_eq!(panicked.value().unwrap_err(), AppError::Library("boom".to_string()));
+    }
+
+    #[test]
+    fn test_then_raise() {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let mut p: Promise<usize> = Promise::new();
+        p.set_interrupt_handler(|_| {
+            COUNTER.fetch_add(1, Ordering::SeqCst);
+        });
+        let f = p.get_future()
+            .unwrap()
+            .then_val(|try| try.value().unwrap() + 1)
+            .unwrap()
+            .map_error(|err| err)
+            .unwrap();
+        f.raise(Error::new(ErrorKind::Interrupted, "no longer needed"));
+        assert_eq!(COUNTER.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_executor_runs_callback() {
+        use std::sync::Arc;
+
+        let manual = Arc::new(ManualExecutor::new());
+        let mut p: Promise<usize> = Promise::new();
+        let mut f = p.get_future().unwrap();
+        f.set_executor(manual.clone());
+        let (tx, rx) = channel();
+        f.tap(move |try| {
+                tx.send(*try.ok().unwrap()).unwrap();
+            })
+            .unwrap();
+        p.set_value(1).unwrap();
+        assert!(rx.try_recv().is_err());
+        assert_eq!(manual.run(), 1);
+        assert_eq!(rx.try_recv().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_future_get_executor() {
+        use std::sync::Arc;
+
+        let mut p: Promise<usize> = Promise::new();
+        let mut f = p.get_future().unwrap();
+        assert!(f.get_executor().is_none());
+        assert!(Future::new(Try::new_value(1)).get_executor().is_none());
+        // Without an executor the downstream future doesn't get one either
+        assert!(f.then_val(|try| try.value().unwrap()).unwrap().get_executor().is_none());
+
+        let f = Future::new_on(Try::new_value(1), Arc::new(ManualExecutor::new()));
+        assert!(f.get_executor().is_some());
+        p.set_value(1).unwrap();
+    }
+
+    #[test]
+    fn test_future_defer() {
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        let manual = Arc::new(ManualExecutor::new());
+        let ran = Arc::new(AtomicBool::new(false));
+        let ran2 = ran.clone();
+        let mut f = Future::defer(manual.clone(), move || {
+            ran2.store(true, Ordering::SeqCst);
+            Try::new_value(7)
+        });
+        // Nothing runs until the executor is drained
+        assert!(!ran.load(Ordering::SeqCst));
+        assert!(f.peek().is_none());
+        assert_eq!(manual.run(), 1);
+        assert!(ran.load(Ordering::SeqCst));
+        assert_eq!(f.value().unwrap(), 7);
+    }
+
+    #[test]
+    fn test_future_defer_value() {
+        use executor::ThreadPoolExecutor;
+        use std::sync::Arc;
+
+        let pool = Arc::new(ThreadPoolExecutor::new(2));
+        let f = Future::defer_value(pool.clone(), || (1..11).sum::<usize>());
+        assert_eq!(f.wait().unwrap(), 55);
+
+        let f = Future::defer_value(pool, || -> usize { panic!("offloaded work failed") });
+        assert_eq!(f.wait().unwrap_err().to_string(), "offloaded work failed");
+    }
+
+    #[test]
+    fn test_request_context_on_thread_pool() {
+        use executor::ThreadPoolExecutor;
+        use request::RequestContext;
+        use std::sync::Arc;
+
+        // One thread, so the last check runs where the callbacks did
+        let pool = Arc::new(ThreadPoolExecutor::new(1));
+        let prev = RequestContext::set_context(Arc::new(RequestContext::new()));
+        RequestContext::save_context().set_context_data("trace_id", 42 as u64);
+        let mut p: Promise<usize> = Promise::new();
+        let mut future = p.get_future().unwrap();
+        future.set_executor(pool.clone());
+        let f = future.then_val(|try| {
+                let ctxt = RequestContext::save_context();
+                (try.value().unwrap(), ctxt.get_context_data::<u64>("trace_id"))
+            })
+            .unwrap()
+            .then_val(|try| {
+                let ctxt = RequestContext::save_context();
+                (try.value().unwrap(), ctxt.get_context_data::<u64>("trace_id"))
+            })
+            .unwrap();
+        RequestContext::set_context(prev);
+        p.set_value(1).unwrap();
+        assert_eq!(f.wait().unwrap(), ((1, Some(42)), Some(42)));
+        // The pool thread's own context is back once the callbacks are done
+        let leaked = Future::defer_value(pool, || {
+            RequestContext::save_context().has_context_data("trace_id")
+        });
+        assert!(!leaked.wait().unwrap());
+    }
+
+    #[test]
+    fn test_future_then_val_on_executor() {
+        use std::sync::Arc;
+        use std::sync::atomic::AtomicBool;
+
+        let manual = Arc::new(ManualExecutor::new());
+        let mut p: Promise<usize> = Promise::new();
+        let mut future = p.get_future().unwrap();
+        future.set_executor(manual.clone());
+        let ran = Arc::new(AtomicBool::new(false));
+        let ran2 = ran.clone();
+        let mut f = future.then_val(move |try| {
+                ran2.store(true, Ordering::SeqCst);
+                try.value().unwrap() + 1
+            })
+            .unwrap();
+        p.set_value(1).unwrap();
+        // Setting the result queued the body rather than running it
+        assert!(!ran.load(Ordering::SeqCst));
+        assert!(f.peek().is_none());
+        assert_eq!(manual.run(), 1);
+        assert!(ran.load(Ordering::SeqCst));
+        assert_eq!(f.value().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_future_boxed() {
+        use super::BoxedFuture;
+
+        let mut p: Promise<String> = Promise::new();
+        let pipelines: Vec<BoxedFuture> = vec![Future::new(Try::new_value(1 as usize)).boxed(),
+                                               p.get_future().unwrap().boxed()];
+        p.set_value(String::from("done")).unwrap();
+        let mut drained = Vec::new();
+        for mut f in pipelines {
+            let val = f.value().unwrap();
+            if let Some(n) = val.downcast_ref::<usize>() {
+                drained.push(n.to_string());
+            } else if let Some(s) = val.downcast_ref::<String>() {
+                drained.push(s.clone());
+            }
+        }
+        assert_eq!(drained, vec!["1", "done"]);
+
+        let f = Future::new(Try::new_value(1 as usize)).boxed();
+        assert_eq!(f.downcast::<usize>().value().unwrap(), 1);
+        let err = Future::new(Try::new_value(1 as usize))
+            .boxed()
+            .downcast::<String>()
+            .value()
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_spawn() {
+        use executor::ThreadPoolExecutor;
+        use std::sync::Arc;
+
+        let pool = Arc::new(ThreadPoolExecutor::new(4));
+        let futures: Vec<Future<usize>> =
+            (0..8).map(|i| spawn(pool.clone(), move || i * i)).collect();
+        assert_eq!(collect(futures).wait().unwrap(),
+                   vec![0, 1, 4, 9, 16, 25, 36, 49]);
+    }
+
+    #[test]
+    fn test_future_new_on() {
+        use std::sync::Arc;
+
+        let manual = Arc::new(ManualExecutor::new());
+        let mut f = Future::new_on(Try::new_value(1), manual.clone());
+        let (tx, rx) = channel();
+        f.tap(move |try| {
+                tx.send(*try.ok().unwrap()).unwrap();
+            })
+            .unwrap();
+        // The result is already set, yet the callback waits for the executor
+        assert!(rx.try_recv().is_err());
+        assert_eq!(manual.run(), 1);
+        assert_eq!(rx.try_recv().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_executor_outlives_caller() {
+        use std::sync::Arc;
+
+        let mut p: Promise<usize> = Promise::new();
+        let f = p.get_future().unwrap();
+        let timer = Arc::new(TimerExecutor::new());
+        f.set_executor(timer.clone());
+        // The future's reference keeps the timer, and its thread, running
+        drop(timer);
+        p.set_value(2).unwrap();
+        assert_eq!(f.wait().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_collect_tuple2() {
+        let mut p: Promise<String> = Promise::new();
+        let f = collect_tuple2(Future::new(Try::new_value(1)), p.get_future().unwrap());
+        p.set_value(String::from("two")).unwrap();
+        assert_eq!(f.wait().unwrap(), (1, String::from("two")));
+    }
+
+    #[test]
+    fn test_collect_tuple2_error() {
+        let mut p: Promise<String> = Promise::new();
+        let f = collect_tuple2(Future::new(Try::new_value(1)), p.get_future().unwrap());
+        p.set_error_direct(Error::new(ErrorKind::TimedOut, "slow")).unwrap();
+        assert_eq!(f.wait().unwrap_err().kind(), ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn test_collect_tuple3() {
+        let f = collect_tuple3(Future::new(Try::new_value(1)),
+                               Future::new(Try::new_value("two")),
+                               Future::new(Try::new_value(3.0)));
+        assert_eq!(f.wait().unwrap(), (1, "two", 3.0));
+    }
+
+    #[test]
+    fn test_future_then_via() {
+        use std::sync::Arc;
+
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let manual = Arc::new(ManualExecutor::new());
+        let other = Arc::new(ManualExecutor::new());
+        let mut future = Future::new(Try::new_value(1));
+        future.set_executor(other.clone());
+        let mut f = future.then_via(manual.clone(), |try| {
+                COUNTER.fetch_add(1, Ordering::SeqCst);
+                return Future::new(Try::new_value(try.value().unwrap() + 1));
+            })
+            .unwrap();
+        // The source's executor runs the hop, but not the continuation
+        assert_eq!(other.run(), 1);
+        assert_eq!(COUNTER.load(Ordering::SeqCst), 0);
+        assert_eq!(manual.run(), 1);
+        assert_eq!(COUNTER.load(Ordering::SeqCst), 1);
+        assert_eq!(f.value().unwrap(), 2);
     }
 }
@@ -1,18 +1,23 @@
 #![feature(asm)]
+#![feature(attr_literals)]
 #![feature(const_fn)]
 #![feature(fnbox)]
-#![feature(repr_simd)]
+#![feature(repr_align)]
 #![feature(test)]
 #![feature(unboxed_closures)]
 
 extern crate libc;
 extern crate test;
 
+pub mod error;
 pub mod executor;
 pub mod microspinlock;
 #[macro_use]
 pub mod scopeguard;
 pub mod future;
 pub mod promise;
+pub mod request;
+#[cfg(test)]
+mod alloc_count;
 mod detail;
 mod try;
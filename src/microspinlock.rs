@@ -1,7 +1,15 @@
+use std::cell::UnsafeCell;
+use std::ops::{Deref, DerefMut};
+use std::sync::{LockResult, PoisonError, TryLockError, TryLockResult};
 use std::sync::atomic::{AtomicBool, ATOMIC_BOOL_INIT, Ordering};
+#[cfg(debug_assertions)]
+use std::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT};
 use std::thread;
+use std::time::{Duration, Instant};
 
-use libc::{nanosleep, timespec};
+use libc::{c_long, nanosleep, time_t, timespec};
+#[cfg(debug_assertions)]
+use libc::pthread_self;
 
 /// Called while spinning (name borrowed from Linux). Can be implemented to call
 /// a platform-specific method of lightening CPU load in spinlocks.
@@ -20,33 +28,135 @@ fn cpu_relax() {
 fn cpu_relax() {}
 
 /// A helper object for the contended case. Starts off with eager
-/// spinning, and falls back to sleeping for small quantums.
-struct Sleeper {
+/// spinning, then yields to the scheduler for a while, and finally falls
+/// back to sleeping for small quantums.
+pub struct Sleeper {
     spin_count: u32,
+    max_active_spin: u32,
+    max_yield: u32,
+    sleep_time: timespec,
+    deadlock: DeadlockCheck,
 }
 
 const MAX_ACTIVE_SPIN: u32 = 4000;
 
+// Yielding lets another thread run, likely the lock holder, without the
+// cost of a timer sleep. Past this many yields the holder is presumably
+// busy for a while, so sleep instead.
+const MAX_YIELD: u32 = 64;
+
+// Always sleep 0.5ms, assuming this will make the kernel put
+// us down for whatever its minimum timer resolution is (in
+// linux this varies by kernel version from 1ms to 10ms).
+const SLEEP_NSEC: c_long = 500000;
+
+// Nothing should hold a MicroSpinLock for anywhere near this long, so in
+// debug builds a lock() still waiting after this gives up and panics.
+const DEADLOCK_TIMEOUT_SECS: u64 = 10;
+
+/// Tracks how long a lock() has been waiting in debug builds, and panics
+/// once it has waited past the timeout.
+#[cfg(debug_assertions)]
+struct DeadlockCheck {
+    timeout: Duration,
+    started: Option<Instant>,
+}
+
+#[cfg(debug_assertions)]
+impl DeadlockCheck {
+    fn new(timeout: Duration) -> DeadlockCheck {
+        DeadlockCheck {
+            timeout: timeout,
+            started: None,
+        }
+    }
+
+    fn check(&mut self, lock: &MicroSpinLock) {
+        let now = Instant::now();
+        let started = match self.started {
+            Some(started) => started,
+            None => {
+                self.started = Some(now);
+                now
+            }
+        };
+        if now - started > self.timeout {
+            panic!("possible deadlock: lock held by thread {:#x}",
+                   lock.owner.load(Ordering::Relaxed));
+        }
+    }
+}
+
+/// Compiled out of release builds
+#[cfg(not(debug_assertions))]
+struct DeadlockCheck;
+
+#[cfg(not(debug_assertions))]
+impl DeadlockCheck {
+    #[inline(always)]
+    fn new(_timeout: Duration) -> DeadlockCheck {
+        DeadlockCheck
+    }
+
+    #[inline(always)]
+    fn check(&mut self, _lock: &MicroSpinLock) {}
+}
+
 impl Sleeper {
     pub fn new() -> Sleeper {
-        Sleeper { spin_count: 0 }
+        Sleeper {
+            spin_count: 0,
+            max_active_spin: MAX_ACTIVE_SPIN,
+            max_yield: MAX_YIELD,
+            sleep_time: timespec {
+                tv_sec: 0,
+                tv_nsec: SLEEP_NSEC,
+            },
+            deadlock: DeadlockCheck::new(Duration::from_secs(DEADLOCK_TIMEOUT_SECS)),
+        }
+    }
+
+    /// Spin at most `max_active_spin` times before falling back to
+    /// sleeping for `sleep_time` between attempts. Useful on platforms
+    /// where the defaults spin for too long or sleep too coarsely.
+    pub fn with_params(max_active_spin: u32, sleep_time: Duration) -> Sleeper {
+        return Sleeper::with_backoff(max_active_spin, MAX_YIELD, sleep_time);
+    }
+
+    /// Like with_params, but also sets how many times to yield to the
+    /// scheduler after spinning and before sleeping. Zero goes straight from
+    /// spinning to sleeping.
+    pub fn with_backoff(max_active_spin: u32, max_yield: u32, sleep_time: Duration) -> Sleeper {
+        Sleeper {
+            spin_count: 0,
+            max_active_spin: max_active_spin,
+            max_yield: max_yield,
+            sleep_time: timespec {
+                tv_sec: sleep_time.as_secs() as time_t,
+                tv_nsec: sleep_time.subsec_nanos() as c_long,
+            },
+            deadlock: DeadlockCheck::new(Duration::from_secs(DEADLOCK_TIMEOUT_SECS)),
+        }
+    }
+
+    /// In debug builds, how long lock_with waits before deciding the lock
+    /// is deadlocked and panicking with the holder's thread id. Has no
+    /// effect in release builds.
+    pub fn with_deadlock_timeout(mut self, timeout: Duration) -> Sleeper {
+        self.deadlock = DeadlockCheck::new(timeout);
+        return self;
     }
 
     pub fn wait(&mut self) {
-        if self.spin_count < MAX_ACTIVE_SPIN {
+        if self.spin_count < self.max_active_spin {
             self.spin_count += 1;
             cpu_relax();
+        } else if self.spin_count - self.max_active_spin < self.max_yield {
+            self.spin_count += 1;
+            thread::yield_now();
         } else {
-            // Always sleep 0.5ms, assuming this will make the kernel put
-            // us down for whatever its minimum timer resolution is (in
-            // linux this varies by kernel version from 1ms to 10ms).
-            //
-            let sleep_time = timespec {
-                tv_sec: 0,
-                tv_nsec: 500000,
-            };
             unsafe {
-                nanosleep(&sleep_time, 0 as *mut timespec);
+                nanosleep(&self.sleep_time, 0 as *mut timespec);
             }
         }
     }
@@ -54,6 +164,9 @@ impl Sleeper {
 
 pub struct MicroSpinLock {
     lock: AtomicBool,
+    /// The thread holding the lock, for deadlock reports
+    #[cfg(debug_assertions)]
+    owner: AtomicUsize,
 }
 
 const FREE: bool = false;
@@ -62,32 +175,85 @@ const LOCKED: bool = true;
 /// A really, *really* small spinlock for fine-grained locking of lots
 /// of teeny-tiny data.
 impl MicroSpinLock {
+    #[cfg(not(debug_assertions))]
     pub const fn new() -> MicroSpinLock {
         MicroSpinLock { lock: ATOMIC_BOOL_INIT }
     }
 
+    #[cfg(debug_assertions)]
+    pub const fn new() -> MicroSpinLock {
+        MicroSpinLock {
+            lock: ATOMIC_BOOL_INIT,
+            owner: ATOMIC_USIZE_INIT,
+        }
+    }
+
     /// Tries to acquire the spinlock.
     /// Returns true if it acquires it, false otherwise
     pub fn try_lock(&self) -> bool {
-        return self.cas(FREE, LOCKED);
+        if self.cas(FREE, LOCKED) {
+            self.set_owner();
+            return true;
+        }
+        return false;
+    }
+
+    #[cfg(debug_assertions)]
+    fn set_owner(&self) {
+        self.owner.store(unsafe { pthread_self() } as usize, Ordering::Relaxed);
+    }
+
+    #[cfg(not(debug_assertions))]
+    #[inline(always)]
+    fn set_owner(&self) {}
+
+    /// Tries to acquire the spinlock, backing off like lock() does, until
+    /// `dur` has elapsed. A zero `dur` makes a single attempt.
+    /// Returns true if it acquires it, false if the deadline passed first.
+    /// May overshoot the deadline by at most one sleep quantum.
+    pub fn try_lock_for(&self, dur: Duration) -> bool {
+        if self.try_lock() {
+            return true;
+        }
+        let deadline = Instant::now() + dur;
+        let mut sleeper = Sleeper::new();
+        while Instant::now() < deadline {
+            sleeper.wait();
+            if self.lock.load(Ordering::Relaxed) == FREE && self.try_lock() {
+                return true;
+            }
+        }
+        return false;
     }
 
     pub fn lock(&self) {
+        self.lock_with(&mut Sleeper::new());
+    }
+
+    /// Like lock(), but backs off according to the given Sleeper so the
+    /// spinning and sleeping can be tuned per call site.
+    pub fn lock_with(&self, sleeper: &mut Sleeper) {
+        // The spinning loads only decide when to retry the CAS, so they can
+        // be Relaxed. It's the CAS's Acquire, pairing with the Release in
+        // unlock(), that makes the previous holder's writes visible.
         // Manual do-while
-        let mut sleeper = Sleeper::new();
-        while self.lock.load(Ordering::SeqCst) != FREE {
-            sleeper.wait()
+        while self.lock.load(Ordering::Relaxed) != FREE {
+            sleeper.wait();
+            sleeper.deadlock.check(self);
         }
         while !self.try_lock() {
-            while self.lock.load(Ordering::SeqCst) != FREE {
-                sleeper.wait()
+            while self.lock.load(Ordering::Relaxed) != FREE {
+                sleeper.wait();
+                sleeper.deadlock.check(self);
             }
         }
-        debug_assert!(self.lock.load(Ordering::SeqCst) == LOCKED);
+        debug_assert!(self.lock.load(Ordering::Relaxed) == LOCKED);
     }
 
     pub fn unlock(&self) {
-        assert!(self.lock.load(Ordering::SeqCst) == LOCKED);
+        // We hold the lock, so the last write to it was our own CAS. Only
+        // checked in debug builds, keeping release unlock a single store.
+        debug_assert!(self.lock.load(Ordering::Relaxed) == LOCKED);
         self.lock.store(FREE, Ordering::Release);
     }
 
@@ -102,6 +268,172 @@ impl MicroSpinLock {
 
 unsafe impl Sync for MicroSpinLock {}
 
+/// A MicroSpinLock that owns the data it protects, so the data can only be
+/// reached through the guard handed out by lock().
+pub struct SpinMutex<T> {
+    lock: MicroSpinLock,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for SpinMutex<T> {}
+unsafe impl<T: Send> Sync for SpinMutex<T> {}
+
+impl<T> SpinMutex<T> {
+    pub fn new(data: T) -> SpinMutex<T> {
+        SpinMutex {
+            lock: MicroSpinLock::new(),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// Acquires the lock, spinning until it is available. The lock is
+    /// released when the returned guard is dropped.
+    pub fn lock(&self) -> SpinMutexGuard<T> {
+        self.lock.lock();
+        return SpinMutexGuard { mutex: self };
+    }
+
+    /// Tries to acquire the lock without spinning.
+    /// Returns None if the lock is already held
+    pub fn try_lock(&self) -> Option<SpinMutexGuard<T>> {
+        if self.lock.try_lock() {
+            return Some(SpinMutexGuard { mutex: self });
+        }
+        return None;
+    }
+
+    /// Since we have a mutable borrow no locking is needed
+    pub fn get_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.data.get() }
+    }
+
+    pub fn into_inner(self) -> T {
+        unsafe { self.data.into_inner() }
+    }
+}
+
+/// RAII guard for a SpinMutex, unlocks on drop.
+#[must_use]
+pub struct SpinMutexGuard<'a, T: 'a> {
+    mutex: &'a SpinMutex<T>,
+}
+
+impl<'a, T> Deref for SpinMutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<'a, T> DerefMut for SpinMutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<'a, T> Drop for SpinMutexGuard<'a, T> {
+    fn drop(&mut self) {
+        self.mutex.lock.unlock();
+    }
+}
+
+/// A SpinMutex that, like std::sync::Mutex, is poisoned when a thread
+/// panics while holding its guard, since the data may have been left
+/// inconsistent. Use a plain SpinMutex where that check isn't needed.
+pub struct PoisonSpinMutex<T> {
+    mutex: SpinMutex<T>,
+    poisoned: AtomicBool,
+}
+
+impl<T> PoisonSpinMutex<T> {
+    pub fn new(data: T) -> PoisonSpinMutex<T> {
+        PoisonSpinMutex {
+            mutex: SpinMutex::new(data),
+            poisoned: AtomicBool::new(false),
+        }
+    }
+
+    /// Acquires the lock, spinning until it is available.
+    /// Returns an error wrapping the guard if the lock is poisoned
+    pub fn lock(&self) -> LockResult<PoisonSpinMutexGuard<T>> {
+        let guard = PoisonSpinMutexGuard::new(self, self.mutex.lock());
+        if self.is_poisoned() {
+            return Err(PoisonError::new(guard));
+        }
+        return Ok(guard);
+    }
+
+    /// Tries to acquire the lock without spinning.
+    /// Returns WouldBlock if the lock is held, or an error wrapping the
+    /// guard if the lock is poisoned
+    pub fn try_lock(&self) -> TryLockResult<PoisonSpinMutexGuard<T>> {
+        match self.mutex.try_lock() {
+            Some(guard) => {
+                let guard = PoisonSpinMutexGuard::new(self, guard);
+                if self.is_poisoned() {
+                    return Err(TryLockError::Poisoned(PoisonError::new(guard)));
+                }
+                return Ok(guard);
+            }
+            None => {
+                return Err(TryLockError::WouldBlock);
+            }
+        }
+    }
+
+    pub fn is_poisoned(&self) -> bool {
+        return self.poisoned.load(Ordering::Acquire);
+    }
+}
+
+/// RAII guard for a PoisonSpinMutex, poisons the mutex if dropped while
+/// unwinding and unlocks on drop.
+#[must_use]
+pub struct PoisonSpinMutexGuard<'a, T: 'a> {
+    guard: SpinMutexGuard<'a, T>,
+    poisoned: &'a AtomicBool,
+    /// Whether we were already panicking when the lock was taken, in which
+    /// case dropping during that same unwind shouldn't poison
+    panicking: bool,
+}
+
+impl<'a, T> PoisonSpinMutexGuard<'a, T> {
+    fn new(mutex: &'a PoisonSpinMutex<T>,
+           guard: SpinMutexGuard<'a, T>)
+           -> PoisonSpinMutexGuard<'a, T> {
+        PoisonSpinMutexGuard {
+            guard: guard,
+            poisoned: &mutex.poisoned,
+            panicking: thread::panicking(),
+        }
+    }
+}
+
+impl<'a, T> Deref for PoisonSpinMutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &*self.guard
+    }
+}
+
+impl<'a, T> DerefMut for PoisonSpinMutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut *self.guard
+    }
+}
+
+impl<'a, T> Drop for PoisonSpinMutexGuard<'a, T> {
+    fn drop(&mut self) {
+        // The inner guard unlocks after this, so the poison flag is
+        // visible to whoever acquires the lock next.
+        if !self.panicking && thread::panicking() {
+            self.poisoned.store(true, Ordering::Release);
+        }
+    }
+}
+
 /// Stolen from aturon's [crossbeam](https://github.com/aturon/crossbeam)
 /// Like `std::thread::spawn`, but without the closure bounds.
 pub unsafe fn spawn_unsafe<'a, F>(f: F) -> thread::JoinHandle<()>
@@ -157,11 +489,143 @@ fn test_microspinlock_spin() {
     let _res = child.join();
 }
 
+#[test]
+fn test_microspinlock_try_lock_for() {
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    let spinlock = MicroSpinLock::new();
+    spinlock.lock();
+    let child = unsafe {
+        spawn_unsafe(|| {
+            // Hold the lock for 200 milliseconds then release it
+            thread::sleep(Duration::from_millis(200));
+            spinlock.unlock();
+        })
+    };
+    let start = Instant::now();
+    assert!(!spinlock.try_lock_for(Duration::from_millis(50)));
+    assert!(start.elapsed() >= Duration::from_millis(50));
+    assert!(!spinlock.try_lock_for(Duration::new(0, 0)));
+    assert!(spinlock.try_lock_for(Duration::from_secs(5)));
+    spinlock.unlock();
+    let _res = child.join();
+}
+
+#[cfg(debug_assertions)]
+#[test]
+fn test_microspinlock_deadlock() {
+    let spinlock = MicroSpinLock::new();
+    spinlock.lock();
+    let child = unsafe {
+        spawn_unsafe(|| {
+            let mut sleeper = Sleeper::new().with_deadlock_timeout(Duration::from_millis(50));
+            spinlock.lock_with(&mut sleeper);
+        })
+    };
+    let payload = child.join().unwrap_err();
+    let msg = payload.downcast_ref::<String>().unwrap();
+    assert_eq!(*msg,
+               format!("possible deadlock: lock held by thread {:#x}",
+                       unsafe { pthread_self() } as usize));
+    spinlock.unlock();
+}
+
+#[test]
+fn test_microspinlock_counter() {
+    use std::cell::UnsafeCell;
+
+    // Only ever touched with the lock held
+    struct Unsynced(UnsafeCell<u64>);
+    unsafe impl Sync for Unsynced {}
+
+    let spinlock = MicroSpinLock::new();
+    let counter = Unsynced(UnsafeCell::new(0));
+    let children: Vec<_> = (0..8)
+        .map(|_| unsafe {
+            spawn_unsafe(|| {
+                for _ in 0..10000 {
+                    spinlock.lock();
+                    *counter.0.get() += 1;
+                    spinlock.unlock();
+                }
+            })
+        })
+        .collect();
+    for child in children {
+        child.join().unwrap();
+    }
+    assert_eq!(unsafe { *counter.0.get() }, 80000);
+}
+
+#[test]
+fn test_spin_mutex_counter() {
+    use std::sync::Arc;
+    use std::thread;
+
+    let counter = Arc::new(SpinMutex::new(0 as u64));
+    let children: Vec<_> = (0..8)
+        .map(|_| {
+            let counter = counter.clone();
+            thread::spawn(move || {
+                for _ in 0..10000 {
+                    *counter.lock() += 1;
+                }
+            })
+        })
+        .collect();
+    for child in children {
+        child.join().unwrap();
+    }
+    assert_eq!(*counter.lock(), 80000);
+}
+
+#[test]
+fn test_spin_mutex_try_lock() {
+    let mutex = SpinMutex::new(5);
+    {
+        let guard = mutex.lock();
+        assert_eq!(*guard, 5);
+        assert!(mutex.try_lock().is_none());
+    }
+    *mutex.try_lock().unwrap() = 6;
+    assert_eq!(mutex.into_inner(), 6);
+}
+
+#[test]
+fn test_poison_spin_mutex() {
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::TryLockError;
+
+    let mutex = PoisonSpinMutex::new(1);
+    *mutex.lock().unwrap() += 1;
+    assert!(!mutex.is_poisoned());
+    let res = panic::catch_unwind(AssertUnwindSafe(|| {
+        let mut guard = mutex.lock().unwrap();
+        *guard += 1;
+        panic!("panicking while holding the lock");
+    }));
+    assert!(res.is_err());
+    assert!(mutex.is_poisoned());
+    // The lock was still released and the data is still reachable
+    match mutex.lock() {
+        Ok(_) => panic!("expected the mutex to be poisoned"),
+        Err(poisoned) => assert_eq!(*poisoned.into_inner(), 3),
+    }
+    let res = mutex.try_lock();
+    match res {
+        Err(TryLockError::Poisoned(_)) => {}
+        _ => panic!("expected the mutex to be poisoned"),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use test::Bencher;
     use super::*;
     use std::sync::Mutex;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::Duration;
 
     #[bench]
     fn bench_uncontended_microspinlock(b: &mut Bencher) {
@@ -172,6 +636,115 @@ mod tests {
         })
     }
 
+    /// bench_uncontended_microspinlock with unlock checking the lock is held
+    /// in release builds too, as it used to
+    #[bench]
+    fn bench_uncontended_microspinlock_checked_unlock(b: &mut Bencher) {
+        let spinlock = MicroSpinLock::new();
+        b.iter(|| {
+            spinlock.lock();
+            assert!(spinlock.lock.load(Ordering::Relaxed) == LOCKED);
+            spinlock.lock.store(FREE, Ordering::Release);
+        })
+    }
+
+    /// Measures lock/unlock throughput while another thread is hammering
+    /// the same lock, backing off with the given spin limit.
+    fn bench_contended(b: &mut Bencher, max_active_spin: u32) {
+        let spinlock = MicroSpinLock::new();
+        let done = AtomicBool::new(false);
+        let child = unsafe {
+            spawn_unsafe(|| {
+                while !done.load(Ordering::Acquire) {
+                    spinlock.lock();
+                    spinlock.unlock();
+                }
+            })
+        };
+        b.iter(|| {
+            let mut sleeper = Sleeper::with_params(max_active_spin, Duration::new(0, 500000));
+            spinlock.lock_with(&mut sleeper);
+            spinlock.unlock();
+        });
+        done.store(true, Ordering::Release);
+        let _res = child.join();
+    }
+
+    #[bench]
+    fn bench_contended_microspinlock_spin_4000(b: &mut Bencher) {
+        bench_contended(b, 4000);
+    }
+
+    #[bench]
+    fn bench_contended_microspinlock_spin_10(b: &mut Bencher) {
+        bench_contended(b, 10);
+    }
+
+    /// Lock/unlock throughput with three other threads hammering the lock
+    #[bench]
+    fn bench_contended_microspinlock_4_threads(b: &mut Bencher) {
+        let spinlock = MicroSpinLock::new();
+        let done = AtomicBool::new(false);
+        let children: Vec<_> = (0..3)
+            .map(|_| unsafe {
+                spawn_unsafe(|| {
+                    while !done.load(Ordering::Acquire) {
+                        spinlock.lock();
+                        spinlock.unlock();
+                    }
+                })
+            })
+            .collect();
+        b.iter(|| {
+            spinlock.lock();
+            spinlock.unlock();
+        });
+        done.store(true, Ordering::Release);
+        for child in children {
+            let _res = child.join();
+        }
+    }
+
+    /// Lock/unlock throughput with seven other threads hammering the lock,
+    /// yielding up to `max_yield` times between spinning and sleeping.
+    fn bench_contended_8_threads(b: &mut Bencher, max_yield: u32) {
+        let spinlock = MicroSpinLock::new();
+        let done = AtomicBool::new(false);
+        let children: Vec<_> = (0..7)
+            .map(|_| unsafe {
+                spawn_unsafe(|| {
+                    while !done.load(Ordering::Acquire) {
+                        let mut sleeper = Sleeper::with_backoff(MAX_ACTIVE_SPIN,
+                                                                max_yield,
+                                                                Duration::new(0, 500000));
+                        spinlock.lock_with(&mut sleeper);
+                        spinlock.unlock();
+                    }
+                })
+            })
+            .collect();
+        b.iter(|| {
+            let mut sleeper =
+                Sleeper::with_backoff(MAX_ACTIVE_SPIN, max_yield, Duration::new(0, 500000));
+            spinlock.lock_with(&mut sleeper);
+            spinlock.unlock();
+        });
+        done.store(true, Ordering::Release);
+        for child in children {
+            let _res = child.join();
+        }
+    }
+
+    #[bench]
+    fn bench_contended_microspinlock_8_threads_yield(b: &mut Bencher) {
+        bench_contended_8_threads(b, MAX_YIELD);
+    }
+
+    #[bench]
+    fn bench_contended_microspinlock_8_threads_no_yield(b: &mut Bencher) {
+        bench_contended_8_threads(b, 0);
+    }
+
     #[bench]
     fn bench_uncontended_mutex(b: &mut Bencher) {
         let mutex = Mutex::new(0);
@@ -1,32 +1,71 @@
-use std::io::{Error, ErrorKind};
+use std::fmt;
+use std::io::Error;
+use std::mem;
 use std::ptr;
+use std::sync::Arc;
 
 use detail::core::Core;
+use error::FutureError;
 use future::Future;
-use try::Try;
+use microspinlock::SpinMutex;
+use try::{Try, TryUsageError};
 
-pub struct Promise<T> {
-    pub core_ptr: *mut Core<T>,
+/// The producer side of a Core. Like Try, the error type `E` defaults to
+/// io::Error; new() makes a Promise with the default, Promise::default() one
+/// with any `E` an io::Error converts into, as the library's own errors,
+/// such as BrokenPromise, are handed out as io::Errors.
+pub struct Promise<T, E = Error> {
+    pub core_ptr: *mut Core<T, E>,
     pub retrieved: bool,
 }
 
-impl<T> Drop for Promise<T> {
+impl<T, E> Drop for Promise<T, E> {
     fn drop(&mut self) {
         self.detach();
     }
 }
 
+/// Like Future, a Promise can be fulfilled from another thread.
+unsafe impl<T: Send, E: Send> Send for Promise<T, E> {}
+
+impl<T, E> fmt::Debug for Promise<T, E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.core_ptr.is_null() {
+            return write!(f, "Promise {{ core: null, retrieved: {} }}", self.retrieved);
+        }
+        let (state, has_result) = unsafe { (*self.core_ptr).debug_state() };
+        f.debug_struct("Promise")
+            .field("state", &state)
+            .field("has_result", &has_result)
+            .field("retrieved", &self.retrieved)
+            .finish()
+    }
+}
+
 impl<T> Promise<T> {
     pub fn new() -> Promise<T> {
+        return Promise::default();
+    }
+}
+
+impl<T, E> Default for Promise<T, E>
+    where E: From<Error>
+{
+    fn default() -> Promise<T, E> {
         Promise {
             retrieved: false,
             core_ptr: Box::into_raw(Box::new(Core::new())),
         }
     }
+}
 
+impl<T, E> Promise<T, E> {
     fn detach(&mut self) {
         unsafe {
             if !self.core_ptr.is_null() {
+                // The Core counts a Future side from the start, so if the
+                // Future was never handed out detach it on its behalf.
+                // Whichever detach brings the count to zero frees the Core.
                 if !self.retrieved {
                     (*self.core_ptr).detach_future();
                 }
@@ -39,7 +78,7 @@ impl<T> Promise<T> {
     fn error_if_retrieved(&self) -> Result<(), Error> {
         // TODO(ptc) use UNLIKELY in future
         if self.retrieved {
-            return Err(Error::new(ErrorKind::Other, "Promise already retrieved"));
+            return Err(FutureError::AlreadyRetrieved.into());
         }
         return Ok(());
     }
@@ -47,32 +86,419 @@ impl<T> Promise<T> {
     fn error_if_fulfilled(&self) -> Result<(), Error> {
         // TODO(ptc) Use UNLIKELY for both tests
         if self.core_ptr.is_null() {
-            return Err(Error::new(ErrorKind::Other, "No state"));
+            return Err(FutureError::NoState.into());
         }
         if unsafe { (*self.core_ptr).ready() } {
-            return Err(Error::new(ErrorKind::Other, "Promise already satisfied"));
+            return Err(FutureError::AlreadySatisfied.into());
         }
         return Ok(());
     }
 
-    pub fn set_try(&self, try: Try<T>) -> Result<(), Error> {
+    pub fn is_fulfilled(&self) -> bool {
+        if self.core_ptr.is_null() {
+            return false;
+        }
+        unsafe { (*self.core_ptr).ready() }
+    }
+
+    /// Register a handler to be called when the consumer raises an
+    /// interrupt, e.g. to cancel the work backing this promise. If an
+    /// interrupt was already raised the handler is called immediately. The
+    /// handler runs on whichever thread raises, hence Send and Sync.
+    pub fn set_interrupt_handler<F>(&self, handler: F)
+        where F: Fn(&Error) + Send + Sync + 'static
+    {
+        if self.core_ptr.is_null() {
+            return;
+        }
+        unsafe {
+            (*self.core_ptr).set_interrupt_handler(Arc::new(handler));
+        }
+    }
+
+    pub fn set_try(&self, try: Try<T, E>) -> Result<(), Error> {
         try!(self.error_if_fulfilled());
         unsafe {
             return (*self.core_ptr).set_result(try);
         }
     }
 
-    pub fn set_error<U>(&self, try: Try<U>) -> Result<(), Error> {
+    pub fn set_error<U>(&self, try: Try<U, E>) -> Result<(), Error>
+        where E: From<TryUsageError>
+    {
         try!(self.error_if_fulfilled());
         unsafe {
             return (*self.core_ptr).set_result(Try::new_error(try.get_error()));
         }
     }
 
-    pub fn get_future(&mut self) -> Result<Future<T>, Error> {
-        // TODO(ptc) Implement get_future
+    pub fn set_value(&self, val: T) -> Result<(), Error> {
+        return self.set_try(Try::new_value(val));
+    }
+
+    pub fn set_error_direct(&self, err: E) -> Result<(), Error> {
+        return self.set_try(Try::new_error(err));
+    }
+
+    /// Fulfill with the result of calling `func`. If `func` panics the
+    /// promise is fulfilled with an error carrying the panic message instead.
+    pub fn set_with<F>(&self, func: F) -> Result<(), Error>
+        where F: FnOnce() -> T,
+              E: From<Error>
+    {
+        try!(self.error_if_fulfilled());
+        return self.set_try(Try::with_panic(func).map_error(E::from));
+    }
+
+    /// Hand out the Future for this Promise. Fails with AlreadyRetrieved
+    /// if it has been handed out already, unless that Future was dropped
+    /// unused before the Promise was fulfilled, in which case it is
+    /// replaced.
+    pub fn get_future(&mut self) -> Result<Future<T, E>, Error> {
+        if self.retrieved && !self.core_ptr.is_null() &&
+           unsafe { (*self.core_ptr).reattach_future() } {
+            return Ok(unsafe { Future::new_core_ptr(self.core_ptr) });
+        }
         try!(self.error_if_retrieved());
         self.retrieved = true;
-        return Ok(Future::new_core_ptr(self.core_ptr));
+        // retrieved guarantees this is the only Future for the Core
+        return Ok(unsafe { Future::new_core_ptr(self.core_ptr) });
+    }
+}
+
+struct SharedState<T> {
+    promises: Vec<Promise<T>>,
+    result: Option<Try<T>>,
+}
+
+/// A Promise that can hand out any number of Futures, all of which are
+/// fulfilled with a copy of the same result.
+pub struct SharedPromise<T> {
+    state: SpinMutex<SharedState<T>>,
+}
+
+impl<T> SharedPromise<T>
+    where T: Clone
+{
+    pub fn new() -> SharedPromise<T> {
+        SharedPromise {
+            state: SpinMutex::new(SharedState {
+                promises: Vec::new(),
+                result: None,
+            }),
+        }
+    }
+
+    pub fn is_fulfilled(&self) -> bool {
+        return self.state.lock().result.is_some();
+    }
+
+    /// Futures retrieved after fulfillment are already completed with a
+    /// copy of the result.
+    pub fn get_future(&self) -> Result<Future<T>, Error> {
+        let mut state = self.state.lock();
+        if let Some(ref try) = state.result {
+            return Ok(Future::new(try.clone()));
+        }
+        let mut p = Promise::new();
+        let f = try!(p.get_future());
+        state.promises.push(p);
+        return Ok(f);
+    }
+
+    pub fn set_try(&self, try: Try<T>) -> Result<(), Error> {
+        let promises = {
+            let mut state = self.state.lock();
+            if state.result.is_some() {
+                return Err(FutureError::AlreadySatisfied.into());
+            }
+            state.result = Some(try.clone());
+            mem::replace(&mut state.promises, Vec::new())
+        };
+        // Fulfill outside of the lock, callbacks may run inline and call
+        // back into get_future
+        for p in promises {
+            try!(p.set_try(try.clone()));
+        }
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::io::{Error, ErrorKind};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::{Promise, SharedPromise};
+    use try::Try;
+
+    #[test]
+    fn test_is_fulfilled() {
+        let p: Promise<usize> = Promise::new();
+        assert!(!p.is_fulfilled());
+        p.set_value(1).unwrap();
+        assert!(p.is_fulfilled());
+    }
+
+    #[test]
+    fn test_interrupt_handler() {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let mut p: Promise<usize> = Promise::new();
+        let _f = p.get_future().unwrap();
+        p.set_interrupt_handler(|err| {
+            assert_eq!(err.kind(), ErrorKind::Interrupted);
+            COUNTER.fetch_add(1, Ordering::SeqCst);
+        });
+        unsafe {
+            (*p.core_ptr).raise(Error::new(ErrorKind::Interrupted, "cancelled"));
+        }
+        assert_eq!(COUNTER.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_interrupt_handler_before_raise() {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let mut p: Promise<usize> = Promise::new();
+        let f = p.get_future().unwrap();
+        p.set_interrupt_handler(|_| {
+            COUNTER.fetch_add(1, Ordering::SeqCst);
+        });
+        assert_eq!(COUNTER.load(Ordering::SeqCst), 0);
+        f.raise(Error::new(ErrorKind::Interrupted, "cancelled"));
+        assert_eq!(COUNTER.load(Ordering::SeqCst), 1);
+        // A second raise is ignored
+        f.raise(Error::new(ErrorKind::Interrupted, "cancelled"));
+        assert_eq!(COUNTER.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_interrupt_handler_after_raise() {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let mut p: Promise<usize> = Promise::new();
+        let f = p.get_future().unwrap();
+        f.raise(Error::new(ErrorKind::Interrupted, "cancelled"));
+        // Called immediately rather than bound
+        p.set_interrupt_handler(|err| {
+            assert_eq!(err.kind(), ErrorKind::Interrupted);
+            COUNTER.fetch_add(1, Ordering::SeqCst);
+        });
+        assert_eq!(COUNTER.load(Ordering::SeqCst), 1);
+        p.set_interrupt_handler(|_| {
+            COUNTER.fetch_add(4, Ordering::SeqCst);
+        });
+        assert_eq!(COUNTER.load(Ordering::SeqCst), 5);
+    }
+
+    #[test]
+    fn test_interrupt_handler_after_result() {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let mut p: Promise<usize> = Promise::new();
+        let f = p.get_future().unwrap();
+        p.set_value(1).unwrap();
+        p.set_interrupt_handler(|_| {
+            COUNTER.fetch_add(1, Ordering::SeqCst);
+        });
+        f.raise(Error::new(ErrorKind::Interrupted, "cancelled"));
+        assert_eq!(COUNTER.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn test_debug() {
+        let mut p: Promise<usize> = Promise::new();
+        assert_eq!(format!("{:?}", p),
+                   "Promise { state: Start, has_result: false, retrieved: false }");
+        let _f = p.get_future().unwrap();
+        p.set_value(1).unwrap();
+        assert_eq!(format!("{:?}", p),
+                   "Promise { state: OnlyResult, has_result: true, retrieved: true }");
+    }
+
+    #[test]
+    fn test_set_value() {
+        let mut p: Promise<usize> = Promise::new();
+        let mut f = p.get_future().unwrap();
+        p.set_value(5).unwrap();
+        assert!(p.set_value(6).is_err());
+        assert_eq!(f.value().unwrap(), 5);
+    }
+
+    #[test]
+    fn test_set_error_direct() {
+        let mut p: Promise<usize> = Promise::new();
+        let mut f = p.get_future().unwrap();
+        p.set_error_direct(Error::new(ErrorKind::TimedOut, "slow")).unwrap();
+        assert!(p.set_error_direct(Error::new(ErrorKind::TimedOut, "slow")).is_err());
+        assert_eq!(f.value().unwrap_err().kind(), ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn test_set_with() {
+        let mut p: Promise<usize> = Promise::new();
+        let mut f = p.get_future().unwrap();
+        p.set_with(|| 2 + 3).unwrap();
+        assert!(p.set_with(|| 6).is_err());
+        assert_eq!(f.value().unwrap(), 5);
+    }
+
+    #[test]
+    fn test_set_with_panic() {
+        let mut p: Promise<usize> = Promise::new();
+        let mut f = p.get_future().unwrap();
+        p.set_with(|| panic!("computation failed")).unwrap();
+        assert_eq!(f.value().unwrap_err().to_string(), "computation failed");
+    }
+
+    /// Counts how many times it's dropped, to tell when a Core is freed
+    /// along with the result inside it.
+    struct DropCounter(&'static AtomicUsize);
+
+    impl Drop for DropCounter {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn test_drop_before_get_future() {
+        static DROPS: AtomicUsize = AtomicUsize::new(0);
+        let p: Promise<DropCounter> = Promise::new();
+        p.set_value(DropCounter(&DROPS)).unwrap();
+        assert_eq!(DROPS.load(Ordering::SeqCst), 0);
+        drop(p);
+        assert_eq!(DROPS.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_drop_promise_with_future_alive() {
+        static DROPS: AtomicUsize = AtomicUsize::new(0);
+        let mut p: Promise<DropCounter> = Promise::new();
+        let f = p.get_future().unwrap();
+        p.set_value(DropCounter(&DROPS)).unwrap();
+        drop(p);
+        // The future still holds the Core
+        assert_eq!(DROPS.load(Ordering::SeqCst), 0);
+        assert!(f.peek().is_some());
+        drop(f);
+        assert_eq!(DROPS.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_drop_future_then_promise() {
+        static DROPS: AtomicUsize = AtomicUsize::new(0);
+        let mut p: Promise<DropCounter> = Promise::new();
+        let f = p.get_future().unwrap();
+        drop(f);
+        p.set_value(DropCounter(&DROPS)).unwrap();
+        assert_eq!(DROPS.load(Ordering::SeqCst), 0);
+        drop(p);
+        assert_eq!(DROPS.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_get_future_twice() {
+        use error::FutureError;
+
+        let mut p: Promise<usize> = Promise::new();
+        let mut f = p.get_future().unwrap();
+        let err = p.get_future().unwrap_err();
+        assert_eq!(FutureError::from_io(&err), Some(FutureError::AlreadyRetrieved));
+        p.set_value(1).unwrap();
+        assert_eq!(f.value().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_get_future_after_drop() {
+        use error::FutureError;
+
+        static DROPS: AtomicUsize = AtomicUsize::new(0);
+        let mut p: Promise<DropCounter> = Promise::new();
+        drop(p.get_future().unwrap());
+        let f = p.get_future().unwrap();
+        p.set_value(DropCounter(&DROPS)).unwrap();
+        drop(p);
+        assert_eq!(DROPS.load(Ordering::SeqCst), 0);
+        drop(f);
+        assert_eq!(DROPS.load(Ordering::SeqCst), 1);
+
+        // Not once the dropped Future has set a callback
+        let mut p: Promise<usize> = Promise::new();
+        p.get_future().unwrap().tap(|_| {}).unwrap();
+        let err = p.get_future().unwrap_err();
+        assert_eq!(FutureError::from_io(&err), Some(FutureError::AlreadyRetrieved));
+
+        // Nor once the Promise is fulfilled
+        let mut p: Promise<usize> = Promise::new();
+        drop(p.get_future().unwrap());
+        p.set_value(1).unwrap();
+        assert!(p.get_future().is_err());
+    }
+
+    #[test]
+    fn test_get_future_after_drop_resets_future_side() {
+        use std::sync::Arc;
+        use std::sync::mpsc::channel;
+
+        use executor::ManualExecutor;
+
+        static INTERRUPTS: AtomicUsize = AtomicUsize::new(0);
+        static READY: AtomicUsize = AtomicUsize::new(0);
+        let mut p: Promise<usize> = Promise::new();
+        p.set_interrupt_handler(|_| {
+            INTERRUPTS.fetch_add(1, Ordering::SeqCst);
+        });
+        {
+            let mut old = p.get_future().unwrap();
+            old.set_executor(Arc::new(ManualExecutor::new()));
+            old.on_ready(|| {
+                READY.fetch_add(1, Ordering::SeqCst);
+            });
+            old.raise(Error::new(ErrorKind::Other, "cancel"));
+        }
+        assert_eq!(INTERRUPTS.load(Ordering::SeqCst), 1);
+
+        let mut f = p.get_future().unwrap();
+        assert!(f.get_executor().is_none());
+        // The interrupt was already delivered, raising again is a no-op
+        f.raise(Error::new(ErrorKind::Other, "again"));
+        assert_eq!(INTERRUPTS.load(Ordering::SeqCst), 1);
+        p.set_value(3).unwrap();
+        assert_eq!(READY.load(Ordering::SeqCst), 0);
+        // No executor, so the callback runs inline
+        let (tx, rx) = channel();
+        f.tap(move |try| tx.send(try.has_value()).unwrap()).unwrap();
+        assert!(rx.try_recv().unwrap());
+    }
+
+    #[test]
+    fn test_shared_promise() {
+        let shared: SharedPromise<usize> = SharedPromise::new();
+        let mut f1 = shared.get_future().unwrap();
+        let mut f2 = shared.get_future().unwrap();
+        let mut f3 = shared.get_future().unwrap();
+        assert!(!shared.is_fulfilled());
+        shared.set_try(Try::new_value(7)).unwrap();
+        assert!(shared.is_fulfilled());
+        assert_eq!(f1.value().unwrap(), 7);
+        assert_eq!(f2.value().unwrap(), 7);
+        assert_eq!(f3.value().unwrap(), 7);
+        // Futures retrieved afterwards see the value too
+        assert_eq!(shared.get_future().unwrap().value().unwrap(), 7);
+        assert!(shared.set_try(Try::new_value(8)).is_err());
+    }
+
+    #[test]
+    fn test_shared_promise_error() {
+        use error::FutureError;
+
+        let shared: SharedPromise<usize> = SharedPromise::new();
+        let mut f1 = shared.get_future().unwrap();
+        let mut f2 = shared.get_future().unwrap();
+        shared.set_try(Try::new_error(FutureError::TimedOut.into())).unwrap();
+        // Every copy of the error is still recognizable
+        let err = f1.value().unwrap_err();
+        assert_eq!(FutureError::from_io(&err), Some(FutureError::TimedOut));
+        let err = f2.value().unwrap_err();
+        assert_eq!(FutureError::from_io(&err), Some(FutureError::TimedOut));
     }
 }
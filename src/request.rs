@@ -0,0 +1,109 @@
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::mem;
+use std::sync::{Arc, RwLock};
+
+/// Per-request data, such as trace ids or deadlines, that follows a request
+/// as it moves between threads. Each thread has a current context, Core
+/// saves it when a callback is set and makes it current again while that
+/// callback runs, wherever that may be.
+pub struct RequestContext {
+    data: RwLock<HashMap<String, Box<Any + Send + Sync>>>,
+}
+
+thread_local!(static CURRENT: RefCell<Arc<RequestContext>>
+              = RefCell::new(Arc::new(RequestContext::new())));
+
+impl RequestContext {
+    pub fn new() -> RequestContext {
+        RequestContext { data: RwLock::new(HashMap::new()) }
+    }
+
+    /// Stash `data` under `token`, replacing anything already stored there.
+    pub fn set_context_data<V>(&self, token: &str, data: V)
+        where V: Any + Send + Sync
+    {
+        self.data.write().unwrap().insert(token.to_string(), Box::new(data));
+    }
+
+    /// Returns a copy of the data stored under `token`, or None if there is
+    /// nothing there or it isn't a `V`.
+    pub fn get_context_data<V>(&self, token: &str) -> Option<V>
+        where V: Any + Clone
+    {
+        let data = self.data.read().unwrap();
+        if let Some(boxed) = data.get(token) {
+            let any: &Any = &**boxed;
+            return any.downcast_ref::<V>().cloned();
+        }
+        return None;
+    }
+
+    pub fn has_context_data(&self, token: &str) -> bool {
+        return self.data.read().unwrap().contains_key(token);
+    }
+
+    pub fn clear_context_data(&self, token: &str) {
+        self.data.write().unwrap().remove(token);
+    }
+
+    /// Make a new, empty context current for this thread and return it.
+    pub fn create() -> Arc<RequestContext> {
+        let ctxt = Arc::new(RequestContext::new());
+        RequestContext::set_context(ctxt.clone());
+        return ctxt;
+    }
+
+    /// Make `ctxt` current for this thread, returning the previous context
+    /// so it can be restored afterwards.
+    pub fn set_context(ctxt: Arc<RequestContext>) -> Arc<RequestContext> {
+        return CURRENT.with(|current| mem::replace(&mut *current.borrow_mut(), ctxt));
+    }
+
+    /// Returns this thread's current context, to be handed to set_context
+    /// later, possibly on another thread.
+    pub fn save_context() -> Arc<RequestContext> {
+        return CURRENT.with(|current| current.borrow().clone());
+    }
+
+    /// Reset this thread's context to a new, empty one, returning the
+    /// previous context.
+    pub fn clear_context() -> Arc<RequestContext> {
+        return RequestContext::set_context(Arc::new(RequestContext::new()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::RequestContext;
+
+    #[test]
+    fn test_context_data() {
+        let ctxt = RequestContext::new();
+        assert!(!ctxt.has_context_data("trace_id"));
+        ctxt.set_context_data("trace_id", 42 as u64);
+        assert!(ctxt.has_context_data("trace_id"));
+        assert_eq!(ctxt.get_context_data::<u64>("trace_id"), Some(42));
+        // Wrong type doesn't match
+        assert_eq!(ctxt.get_context_data::<u32>("trace_id"), None);
+        ctxt.clear_context_data("trace_id");
+        assert_eq!(ctxt.get_context_data::<u64>("trace_id"), None);
+    }
+
+    #[test]
+    fn test_set_save_clear() {
+        let ctxt = RequestContext::create();
+        ctxt.set_context_data("user", String::from("ptc"));
+        assert!(RequestContext::save_context().has_context_data("user"));
+
+        let prev = RequestContext::clear_context();
+        assert!(prev.has_context_data("user"));
+        assert!(!RequestContext::save_context().has_context_data("user"));
+
+        RequestContext::set_context(prev);
+        assert_eq!(RequestContext::save_context().get_context_data::<String>("user"),
+                   Some(String::from("ptc")));
+    }
+}
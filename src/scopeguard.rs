@@ -1,4 +1,14 @@
 use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+use std::thread;
+
+/// When a ScopeGuard runs its cleanup.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum RunOn {
+    Exit,
+    Success,
+    Failure,
+}
 
 #[must_use]
 pub struct ScopeGuard<'a, F>
@@ -6,25 +16,59 @@ pub struct ScopeGuard<'a, F>
 {
     pub cleanup: Option<F>,
     phantom: PhantomData<&'a F>,
+    run_on: RunOn,
+    /// Whether we were already panicking when the guard was created, so
+    /// a guard made during unwinding doesn't count that panic as a failure
+    panicking: bool,
 }
 
 impl<'a, F> ScopeGuard<'a, F>
     where F: 'a + FnOnce()
 {
     pub fn new(func: F) -> ScopeGuard<'a, F> {
+        return ScopeGuard::new_run_on(func, RunOn::Exit);
+    }
+
+    /// Only runs the cleanup if the scope is left normally.
+    pub fn on_success(func: F) -> ScopeGuard<'a, F> {
+        return ScopeGuard::new_run_on(func, RunOn::Success);
+    }
+
+    /// Only runs the cleanup if the scope is left by panicking.
+    pub fn on_failure(func: F) -> ScopeGuard<'a, F> {
+        return ScopeGuard::new_run_on(func, RunOn::Failure);
+    }
+
+    fn new_run_on(func: F, run_on: RunOn) -> ScopeGuard<'a, F> {
         return ScopeGuard {
             cleanup: Some(func),
             phantom: PhantomData,
+            run_on: run_on,
+            panicking: thread::panicking(),
         };
     }
+
+    /// Consume the guard without running its cleanup, e.g. once the
+    /// operation it would roll back has succeeded.
+    pub fn dismiss(mut self) {
+        self.cleanup.take();
+    }
 }
 
 impl<'a, F> Drop for ScopeGuard<'a, F>
     where F: 'a + FnOnce()
 {
     fn drop(&mut self) {
+        let failed = !self.panicking && thread::panicking();
+        let run = match self.run_on {
+            RunOn::Exit => true,
+            RunOn::Success => !failed,
+            RunOn::Failure => failed,
+        };
         if let Some(f) = self.cleanup.take() {
-            f();
+            if run {
+                f();
+            }
         }
     }
 }
@@ -39,6 +83,78 @@ macro_rules! scope_exit {
     };
 }
 
+/// A scope guard over a value, like folly's makeGuard over an object.
+/// The value is reachable through the guard and is moved into the cleanup
+/// when the guard is dropped.
+#[must_use]
+pub struct ValueGuard<V, F>
+    where F: FnOnce(V)
+{
+    value: Option<V>,
+    cleanup: Option<F>,
+}
+
+impl<V, F> ValueGuard<V, F>
+    where F: FnOnce(V)
+{
+    pub fn new(value: V, func: F) -> ValueGuard<V, F> {
+        return ValueGuard {
+            value: Some(value),
+            cleanup: Some(func),
+        };
+    }
+}
+
+impl<V, F> Deref for ValueGuard<V, F>
+    where F: FnOnce(V)
+{
+    type Target = V;
+
+    fn deref(&self) -> &V {
+        self.value.as_ref().unwrap()
+    }
+}
+
+impl<V, F> DerefMut for ValueGuard<V, F>
+    where F: FnOnce(V)
+{
+    fn deref_mut(&mut self) -> &mut V {
+        self.value.as_mut().unwrap()
+    }
+}
+
+impl<V, F> Drop for ValueGuard<V, F>
+    where F: FnOnce(V)
+{
+    fn drop(&mut self) {
+        if let (Some(value), Some(f)) = (self.value.take(), self.cleanup.take()) {
+            f(value);
+        }
+    }
+}
+
+/// Like scope_exit!, but only runs when the scope is left normally
+#[macro_export]
+macro_rules! scope_success {
+    ($e:expr) => {
+        let _gensym = ScopeGuard::on_success(|| { $e })
+    };
+    ($b:block) => {
+        let _gensym = ScopeGuard::on_success(|| { $b })
+    };
+}
+
+/// Like scope_exit!, but only runs when the scope is left by panicking
+#[macro_export]
+macro_rules! scope_fail {
+    ($e:expr) => {
+        let _gensym = ScopeGuard::on_failure(|| { $e })
+    };
+    ($b:block) => {
+        let _gensym = ScopeGuard::on_failure(|| { $b })
+    };
+}
+
 #[test]
 fn test_scope_guard() {
     use std::sync::atomic::{AtomicBool, Ordering};
@@ -58,3 +174,64 @@ fn test_scope_exit_macro() {
     }
     assert_eq!(bool.load(Ordering::Acquire), true);
 }
+
+#[test]
+fn test_scope_guard_dismiss() {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    let bool = AtomicBool::new(false);
+    {
+        let guard = ScopeGuard::new(|| bool.store(true, Ordering::Release));
+        guard.dismiss();
+    }
+    assert_eq!(bool.load(Ordering::Acquire), false);
+}
+
+#[test]
+fn test_scope_success_macro() {
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    let bool = AtomicBool::new(false);
+    {
+        scope_success!(bool.store(true, Ordering::Release));
+    }
+    assert_eq!(bool.load(Ordering::Acquire), true);
+    bool.store(false, Ordering::Release);
+    let res = panic::catch_unwind(AssertUnwindSafe(|| {
+        scope_success!(bool.store(true, Ordering::Release));
+        panic!("leaving the scope by panicking");
+    }));
+    assert!(res.is_err());
+    assert_eq!(bool.load(Ordering::Acquire), false);
+}
+
+#[test]
+fn test_scope_fail_macro() {
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    let bool = AtomicBool::new(false);
+    {
+        scope_fail!(bool.store(true, Ordering::Release));
+    }
+    assert_eq!(bool.load(Ordering::Acquire), false);
+    let res = panic::catch_unwind(AssertUnwindSafe(|| {
+        scope_fail!(bool.store(true, Ordering::Release));
+        panic!("leaving the scope by panicking");
+    }));
+    assert!(res.is_err());
+    assert_eq!(bool.load(Ordering::Acquire), true);
+}
+
+#[test]
+fn test_value_guard() {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    let counter = Arc::new(AtomicUsize::new(0));
+    {
+        let guard = ValueGuard::new(counter.clone(), |handle| {
+            handle.fetch_sub(1, Ordering::AcqRel);
+        });
+        guard.fetch_add(1, Ordering::AcqRel);
+        assert_eq!(guard.load(Ordering::Acquire), 1);
+    }
+    assert_eq!(counter.load(Ordering::Acquire), 0);
+}
@@ -1,4 +1,8 @@
+use std::any::Any;
 use std::io;
+use std::panic::{self, AssertUnwindSafe};
+
+use error::{CallbackPanicked, clone_error};
 
 #[derive(Debug)]
 enum Contains<T, E> {
@@ -7,22 +11,54 @@ enum Contains<T, E> {
     NOTHING,
 }
 
+/// Misuses of a Try that have to be reported through its error type, since
+/// there is no stored error to hand back. Error types used with Try need to
+/// be convertible from this.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryUsageError {
+    /// The Try holds neither a value nor an error
+    Uninitialized,
+    /// Asked for the error of a Try holding a value
+    NotAnError,
+}
+
+impl TryUsageError {
+    pub fn message(&self) -> &'static str {
+        match *self {
+            TryUsageError::Uninitialized => "Using Uninitialized Try",
+            TryUsageError::NotAnError => "Calling get_error on a succesful Try",
+        }
+    }
+}
+
+impl From<TryUsageError> for io::Error {
+    fn from(err: TryUsageError) -> io::Error {
+        io::Error::new(io::ErrorKind::Other, err.message())
+    }
+}
+
+impl From<TryUsageError> for String {
+    fn from(err: TryUsageError) -> String {
+        err.message().to_string()
+    }
+}
+
 /// TODO(ptc) implement Try
 #[derive(Debug)]
-pub struct Try<T> {
-    contains: Contains<T, io::Error>,
+pub struct Try<T, E = io::Error> {
+    contains: Contains<T, E>,
 }
 
-impl<T> Try<T> {
-    pub fn new() -> Try<T> {
+impl<T, E> Try<T, E> {
+    pub fn new() -> Try<T, E> {
         Try { contains: Contains::NOTHING }
     }
 
-    pub fn new_error(err: io::Error) -> Try<T> {
+    pub fn new_error(err: E) -> Try<T, E> {
         Try { contains: Contains::ERROR(err) }
     }
 
-    pub fn new_value(val: T) -> Try<T> {
+    pub fn new_value(val: T) -> Try<T, E> {
         Try { contains: Contains::VALUE(val) }
     }
 
@@ -40,33 +76,189 @@ impl<T> Try<T> {
         }
     }
 
-    pub fn get_error(self) -> io::Error {
+    pub fn is_nothing(&self) -> bool {
         match self.contains {
-            Contains::VALUE(_) => {
-                io::Error::new(io::ErrorKind::Other, "Calling get_error on a succesful Try")
-            }
+            Contains::NOTHING => true,
+            _ => false,
+        }
+    }
+
+    /// Borrow the value without consuming the Try.
+    pub fn ok(&self) -> Option<&T> {
+        match self.contains {
+            Contains::VALUE(ref val) => Some(val),
+            _ => None,
+        }
+    }
+
+    /// Borrow the error without consuming the Try.
+    pub fn err(&self) -> Option<&E> {
+        match self.contains {
+            Contains::ERROR(ref err) => Some(err),
+            _ => None,
+        }
+    }
+
+    /// Returns the value, or `default` if there is an error or nothing.
+    pub fn value_or(self, default: T) -> T {
+        match self.contains {
+            Contains::VALUE(val) => val,
+            _ => default,
+        }
+    }
+
+    /// Transform the value, passing an error or nothing through untouched.
+    pub fn map<U, F>(self, func: F) -> Try<U, E>
+        where F: FnOnce(T) -> U
+    {
+        match self.contains {
+            Contains::VALUE(val) => Try::new_value(func(val)),
+            Contains::ERROR(err) => Try::new_error(err),
+            Contains::NOTHING => Try::new(),
+        }
+    }
+
+    /// Chain a step that can itself fail. An error or nothing passes
+    /// through without calling `func`.
+    pub fn and_then<U, F>(self, func: F) -> Try<U, E>
+        where F: FnOnce(T) -> Try<U, E>
+    {
+        match self.contains {
+            Contains::VALUE(val) => func(val),
+            Contains::ERROR(err) => Try::new_error(err),
+            Contains::NOTHING => Try::new(),
+        }
+    }
+
+    /// Transform the error, passing a value or nothing through untouched.
+    pub fn map_error<E2, F>(self, func: F) -> Try<T, E2>
+        where F: FnOnce(E) -> E2
+    {
+        match self.contains {
+            Contains::VALUE(val) => Try::new_value(val),
+            Contains::ERROR(err) => Try::new_error(func(err)),
+            Contains::NOTHING => Try::new(),
+        }
+    }
+}
+
+impl<T, E> Try<T, E>
+    where E: From<TryUsageError>
+{
+    pub fn get_error(self) -> E {
+        match self.contains {
+            Contains::VALUE(_) => E::from(TryUsageError::NotAnError),
             Contains::ERROR(err) => err,
-            Contains::NOTHING => io::Error::new(io::ErrorKind::Other, "Using Uninitialized Try"),
+            Contains::NOTHING => E::from(TryUsageError::Uninitialized),
         }
     }
 
-    pub fn value(self) -> Result<T, io::Error> {
+    pub fn value(self) -> Result<T, E> {
         match self.contains {
             Contains::VALUE(val) => Ok(val),
             Contains::ERROR(err) => Err(err),
-            Contains::NOTHING => {
-                Err(io::Error::new(io::ErrorKind::Other, "Using Uninitialized Try"))
+            Contains::NOTHING => Err(E::from(TryUsageError::Uninitialized)),
+        }
+    }
+
+    /// Returns the value, or computes one from the error. Nothing is passed
+    /// to `func` as an uninitialized error.
+    pub fn value_or_else<F>(self, func: F) -> T
+        where F: FnOnce(E) -> T
+    {
+        match self.value() {
+            Ok(val) => val,
+            Err(err) => func(err),
+        }
+    }
+}
+
+impl<T, E> Try<Option<T>, E> {
+    /// Swap the nesting, like Result::transpose: a value of None becomes
+    /// None, and anything else is Some of a Try holding what was there.
+    /// Turned back with Try::from.
+    pub fn transpose(self) -> Option<Try<T, E>> {
+        match self.contains {
+            Contains::VALUE(Some(val)) => Some(Try::new_value(val)),
+            Contains::VALUE(None) => None,
+            Contains::ERROR(err) => Some(Try::new_error(err)),
+            Contains::NOTHING => Some(Try::new()),
+        }
+    }
+}
+
+impl<T> Try<T> {
+    /// Run `func` and hold its value, or if it panics a CallbackPanicked
+    /// error carrying the panic message, so a panic can be delivered like
+    /// any other failure.
+    pub fn with_panic<F>(func: F) -> Try<T>
+        where F: FnOnce() -> T
+    {
+        match panic::catch_unwind(AssertUnwindSafe(func)) {
+            Ok(val) => Try::new_value(val),
+            Err(payload) => Try::new_error(CallbackPanicked::new(panic_message(&payload))),
+        }
+    }
+}
+
+fn panic_message(payload: &Box<Any + Send>) -> String {
+    if let Some(msg) = payload.downcast_ref::<&'static str>() {
+        return msg.to_string();
+    }
+    if let Some(msg) = payload.downcast_ref::<String>() {
+        return msg.clone();
+    }
+    return String::from("Callback panicked");
+}
+
+/// io::Error isn't Clone, so a cloned error is rebuilt with clone_error,
+/// which only keeps the payload of this library's own errors.
+impl<T> Clone for Try<T>
+    where T: Clone
+{
+    fn clone(&self) -> Try<T> {
+        match self.contains {
+            Contains::VALUE(ref val) => Try::new_value(val.clone()),
+            Contains::ERROR(ref err) => {
+                Try::new_error(clone_error(err))
             }
+            Contains::NOTHING => Try::new(),
+        }
+    }
+}
+
+impl<T, E> From<Result<T, E>> for Try<T, E> {
+    fn from(res: Result<T, E>) -> Try<T, E> {
+        match res {
+            Ok(val) => Try::new_value(val),
+            Err(err) => Try::new_error(err),
+        }
+    }
+}
+
+impl<T, E> From<Option<Try<T, E>>> for Try<Option<T>, E> {
+    fn from(opt: Option<Try<T, E>>) -> Try<Option<T>, E> {
+        match opt {
+            Some(try) => try.map(Some),
+            None => Try::new_value(None),
         }
     }
 }
 
+impl<T, E> From<Try<T, E>> for Result<T, E>
+    where E: From<TryUsageError>
+{
+    fn from(try: Try<T, E>) -> Result<T, E> {
+        try.value()
+    }
+}
+
 #[cfg(test)]
 mod tests {
 
     use std::io;
 
-    use super::Try;
+    use super::{Try, TryUsageError};
 
     #[test]
     fn test_has_error_has_value() {
@@ -80,4 +272,186 @@ mod tests {
         assert_eq!(error.has_value(), false);
         assert_eq!(error.has_error(), true);
     }
+
+    #[test]
+    fn test_custom_error_type() {
+        let error: Try<u32, String> = Try::new_error(String::from("bad input"));
+        assert!(error.has_error());
+        assert_eq!(error.value(), Err(String::from("bad input")));
+        let value: Try<u32, String> = Try::new_value(7);
+        assert_eq!(value.value(), Ok(7));
+        let empty: Try<u32, String> = Try::new();
+        assert_eq!(empty.value(), Err(String::from("Using Uninitialized Try")));
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum DomainError {
+        Invalid(u32),
+        Misuse(TryUsageError),
+    }
+
+    impl From<TryUsageError> for DomainError {
+        fn from(err: TryUsageError) -> DomainError {
+            DomainError::Misuse(err)
+        }
+    }
+
+    #[test]
+    fn test_domain_error_type() {
+        let error: Try<u32, DomainError> = Try::new_error(DomainError::Invalid(3));
+        assert_eq!(error.get_error(), DomainError::Invalid(3));
+        let value: Try<u32, DomainError> = Try::new_value(3);
+        assert_eq!(value.get_error(),
+                   DomainError::Misuse(TryUsageError::NotAnError));
+    }
+
+    #[test]
+    fn test_map() {
+        let value: Try<usize> = Try::new_value(10);
+        assert_eq!(value.map(|v| v * 2).value().unwrap(), 20);
+        let error: Try<usize> = Try::new_error(io::Error::new(io::ErrorKind::Other, "error"));
+        let mapped = error.map(|v| v * 2);
+        assert!(mapped.has_error());
+        assert_eq!(mapped.get_error().to_string(), "error");
+        let empty: Try<usize> = Try::new();
+        let mapped = empty.map(|v| v * 2);
+        assert!(!mapped.has_value() && !mapped.has_error());
+    }
+
+    #[test]
+    fn test_and_then() {
+        let value: Try<usize> = Try::new_value(10);
+        assert_eq!(value.and_then(|v| Try::new_value(v * 2)).value().unwrap(), 20);
+        let value: Try<usize> = Try::new_value(10);
+        let failed: Try<usize> =
+            value.and_then(|_| Try::new_error(io::Error::new(io::ErrorKind::InvalidInput, "bad")));
+        assert_eq!(failed.get_error().kind(), io::ErrorKind::InvalidInput);
+        let error: Try<usize> = Try::new_error(io::Error::new(io::ErrorKind::Other, "error"));
+        let chained: Try<usize> = error.and_then(|_| panic!("shouldn't run on an error"));
+        assert_eq!(chained.get_error().to_string(), "error");
+        let empty: Try<usize> = Try::new();
+        let chained: Try<usize> = empty.and_then(|_| panic!("shouldn't run on nothing"));
+        assert!(chained.is_nothing());
+    }
+
+    #[test]
+    fn test_map_error() {
+        let value: Try<usize> = Try::new_value(10);
+        let mapped = value.map_error(|err| io::Error::new(io::ErrorKind::TimedOut, err));
+        assert_eq!(mapped.value().unwrap(), 10);
+        let error: Try<usize> = Try::new_error(io::Error::new(io::ErrorKind::Other, "error"));
+        let mapped = error.map_error(|err| io::Error::new(io::ErrorKind::TimedOut, err));
+        assert_eq!(mapped.get_error().kind(), io::ErrorKind::TimedOut);
+        let empty: Try<usize> = Try::new();
+        let mapped = empty.map_error(|err| io::Error::new(io::ErrorKind::TimedOut, err));
+        assert!(!mapped.has_value() && !mapped.has_error());
+    }
+
+    #[test]
+    fn test_from_result() {
+        let value: Try<usize> = Ok(10).into();
+        assert_eq!(value.value().unwrap(), 10);
+        let res: Result<usize, io::Error> = Err(io::Error::new(io::ErrorKind::Other, "error"));
+        let error: Try<usize> = res.into();
+        assert_eq!(error.get_error().to_string(), "error");
+    }
+
+    #[test]
+    fn test_into_result() {
+        let res: Result<usize, io::Error> = Try::new_value(10).into();
+        assert_eq!(res.unwrap(), 10);
+        let error: Try<usize> = Try::new_error(io::Error::new(io::ErrorKind::TimedOut, "error"));
+        let res: Result<usize, io::Error> = error.into();
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::TimedOut);
+        let empty: Try<usize> = Try::new();
+        let res: Result<usize, io::Error> = empty.into();
+        assert_eq!(res.unwrap_err().to_string(), "Using Uninitialized Try");
+    }
+
+    #[test]
+    fn test_transpose() {
+        let none: Try<Option<usize>> = Try::new_value(None);
+        assert!(none.transpose().is_none());
+        let some: Try<Option<usize>> = Try::new_value(Some(10));
+        assert_eq!(some.transpose().unwrap().value().unwrap(), 10);
+        let error: Try<Option<usize>> =
+            Try::new_error(io::Error::new(io::ErrorKind::TimedOut, "error"));
+        assert_eq!(error.transpose().unwrap().get_error().kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn test_from_option() {
+        let none: Try<Option<usize>> = Try::from(None);
+        assert_eq!(none.value().unwrap(), None);
+        let some: Try<Option<usize>> = Try::from(Some(Try::new_value(10)));
+        assert_eq!(some.value().unwrap(), Some(10));
+        let error: Try<Option<usize>> =
+            Some(Try::new_error(io::Error::new(io::ErrorKind::TimedOut, "error"))).into();
+        assert_eq!(error.get_error().kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn test_ok_err_is_nothing() {
+        let value: Try<usize> = Try::new_value(10);
+        assert_eq!(value.ok(), Some(&10));
+        assert!(value.err().is_none());
+        assert!(!value.is_nothing());
+        // Still usable after peeking
+        assert_eq!(value.value().unwrap(), 10);
+        let error: Try<usize> = Try::new_error(io::Error::new(io::ErrorKind::Other, "error"));
+        assert_eq!(error.ok(), None);
+        assert_eq!(error.err().unwrap().to_string(), "error");
+        assert!(!error.is_nothing());
+        let empty: Try<usize> = Try::new();
+        assert_eq!(empty.ok(), None);
+        assert!(empty.err().is_none());
+        assert!(empty.is_nothing());
+    }
+
+    #[test]
+    fn test_value_or() {
+        let value: Try<usize> = Try::new_value(10);
+        assert_eq!(value.value_or(0), 10);
+        let error: Try<usize> = Try::new_error(io::Error::new(io::ErrorKind::Other, "error"));
+        assert_eq!(error.value_or(0), 0);
+        let empty: Try<usize> = Try::new();
+        assert_eq!(empty.value_or(0), 0);
+    }
+
+    #[test]
+    fn test_value_or_else() {
+        let value: Try<String> = Try::new_value(String::from("value"));
+        assert_eq!(value.value_or_else(|err| err.to_string()), "value");
+        let error: Try<String> = Try::new_error(io::Error::new(io::ErrorKind::Other, "error"));
+        assert_eq!(error.value_or_else(|err| err.to_string()), "error");
+        let empty: Try<String> = Try::new();
+        assert_eq!(empty.value_or_else(|err| err.to_string()),
+                   "Using Uninitialized Try");
+    }
+
+    #[test]
+    fn test_clone() {
+        let value: Try<usize> = Try::new_value(10);
+        let cloned = value.clone();
+        assert_eq!(value.value().unwrap(), 10);
+        assert_eq!(cloned.value().unwrap(), 10);
+        let error: Try<usize> = Try::new_error(io::Error::new(io::ErrorKind::TimedOut, "slow"));
+        let cloned = error.clone();
+        let err = cloned.get_error();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(err.to_string(), "slow");
+        assert_eq!(error.get_error().kind(), io::ErrorKind::TimedOut);
+        let empty: Try<usize> = Try::new();
+        assert!(empty.clone().is_nothing());
+    }
+
+    #[test]
+    fn test_with_panic() {
+        let try: Try<usize> = Try::with_panic(|| 2 + 3);
+        assert_eq!(try.value().unwrap(), 5);
+        let try: Try<usize> = Try::with_panic(|| panic!("computation failed"));
+        assert_eq!(try.value().unwrap_err().to_string(), "computation failed");
+        let try: Try<usize> = Try::with_panic(|| panic!("failed with {}", 42));
+        assert_eq!(try.value().unwrap_err().to_string(), "failed with 42");
+    }
 }